@@ -0,0 +1,146 @@
+use crate::mem::Handle;
+
+/// Kind of the errors reported to JS. Exported functions return it as their status code,
+/// or `0` when they succeed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(u8)]
+pub enum ErrorKind {
+    /// The state has not been initialized, there is no current shape, or the call does not
+    /// apply to it (like adding gradient stops to a solid fill).
+    InvalidState = 1,
+    /// The arguments, or the data in the buffers, are malformed.
+    InvalidInput = 2,
+    /// A buffer could not be allocated, or its handle is stale.
+    Memory = 3,
+    /// A surface could not be created, or a shape could not be rendered or exported.
+    Render = 4,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Error {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn invalid_state(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::InvalidState, message)
+    }
+
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::InvalidInput, message)
+    }
+
+    pub fn memory(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Memory, message)
+    }
+
+    pub fn render(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Render, message)
+    }
+
+    /// Serializes the error as its kind (`u8`) followed by its UTF-8 message.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.kind as u8];
+        bytes.extend_from_slice(self.message.as_bytes());
+        bytes
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+static mut LAST_ERROR: Option<Error> = None;
+
+/// Returns the status code of an exported function, keeping its error (if any) so JS can
+/// read it with `get_last_error`.
+pub fn status(result: Result<()>) -> u8 {
+    match result {
+        Ok(()) => 0,
+        Err(error) => {
+            let kind = error.kind as u8;
+            set_last_error(error);
+            kind
+        }
+    }
+}
+
+/// Like [status], for the exported functions returning a pointer. Null is returned when they
+/// fail.
+pub fn pointer_status(result: Result<*mut u8>) -> *mut u8 {
+    match result {
+        Ok(ptr) => ptr,
+        Err(error) => {
+            set_last_error(error);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Set in the status of the exported functions answering a question, see [query_status].
+pub const QUERY_ERROR: u8 = 0x80;
+
+/// Like [status], for the exported functions answering a question. They return `0` or `1`
+/// when they succeed, and the kind of the error with [QUERY_ERROR] set when they fail.
+pub fn query_status(result: Result<bool>) -> u8 {
+    match result {
+        Ok(answer) => answer as u8,
+        Err(error) => {
+            let kind = error.kind as u8;
+            set_last_error(error);
+            QUERY_ERROR | kind
+        }
+    }
+}
+
+/// Like [status], for the exported functions returning a buffer handle. `0` is returned when
+/// they fail.
+pub fn handle_status(result: Result<Handle>) -> u32 {
+    match result {
+        Ok(handle) => handle.into(),
+        Err(error) => {
+            set_last_error(error);
+            0
+        }
+    }
+}
+
+pub fn set_last_error(error: Error) {
+    unsafe { LAST_ERROR = Some(error) };
+}
+
+pub fn last_error() -> Option<&'static Error> {
+    unsafe { LAST_ERROR.as_ref() }
+}
+
+pub fn clear_last_error() {
+    unsafe { LAST_ERROR = None };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_keeps_the_error_until_it_is_cleared() {
+        assert_eq!(status(Ok(())), 0);
+        assert_eq!(status(Err(Error::invalid_input("Bad buffer"))), 2);
+        assert_eq!(
+            last_error().map(Error::to_bytes),
+            Some(b"\x02Bad buffer".to_vec())
+        );
+
+        clear_last_error();
+        assert!(last_error().is_none());
+        assert!(pointer_status(Err(Error::memory("No buffer"))).is_null());
+        assert_eq!(handle_status(Err(Error::memory("Stale handle"))), 0);
+        assert_eq!(query_status(Ok(true)), 1);
+        assert_eq!(query_status(Err(Error::invalid_state("No state"))), 0x81);
+    }
+}
@@ -0,0 +1,321 @@
+use skia::Contains;
+use skia_safe as skia;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::math;
+use crate::shapes::{Kind, Shape};
+use crate::spatial::SpatialIndex;
+
+/// Returns the visible shapes under the given point, in world coordinates, topmost first.
+pub fn shapes_at(
+    point: math::Point,
+    shapes: &HashMap<Uuid, Shape>,
+    index: &SpatialIndex,
+) -> Vec<Uuid> {
+    let area = math::Rect::from_xywh(point.0 - 0.5, point.1 - 0.5, 1., 1.);
+    let hits = index.query(&area).into_iter().filter_map(|id| {
+        let (region, paint_order) = hit_region(&id, shapes, index)?;
+        region.contains(point).then_some((id, paint_order))
+    });
+    topmost_first(hits)
+}
+
+/// Returns the visible shapes inside the given rect, in world coordinates, topmost first.
+/// When `contained` is set, only the shapes fully inside it are returned.
+pub fn shapes_in_rect(
+    rect: math::Rect,
+    contained: bool,
+    shapes: &HashMap<Uuid, Shape>,
+    index: &SpatialIndex,
+) -> Vec<Uuid> {
+    let selection = skia::Path::rect(rect, None);
+    let hits = index.query(&rect).into_iter().filter_map(|id| {
+        let (region, paint_order) = hit_region(&id, shapes, index)?;
+        let is_hit = if contained {
+            rect.contains(region.compute_tight_bounds())
+        } else {
+            region
+                .op(&selection, skia::PathOp::Intersect)
+                .is_some_and(|intersection| !intersection.is_empty())
+        };
+        is_hit.then_some((id, paint_order))
+    });
+    topmost_first(hits)
+}
+
+fn topmost_first(hits: impl Iterator<Item = (Uuid, Vec<usize>)>) -> Vec<Uuid> {
+    let mut hits: Vec<_> = hits.collect();
+    hits.sort_by(|(_, a), (_, b)| b.cmp(a));
+    hits.into_iter().map(|(id, _)| id).collect()
+}
+
+/// Returns the area where the shape can be hit, clipped by its ancestors, along with its
+/// position in the paint order (the index of each ancestor among its siblings, from the root).
+/// Shapes that are hidden, or not painted at all, cannot be hit. Bools are hit on their result,
+/// which the state stores as their path (see [crate::state::State::shapes_at]).
+fn hit_region(
+    id: &Uuid,
+    shapes: &HashMap<Uuid, Shape>,
+    index: &SpatialIndex,
+) -> Option<(skia::Path, Vec<usize>)> {
+    let shape = shapes.get(id)?;
+    if shape.hidden() {
+        return None;
+    }
+
+    let mut region = shape.hit_path();
+    let mut paint_order = vec![];
+    let mut current_id = *id;
+    loop {
+        let parent_id = index.parent(&current_id)?;
+        let parent = shapes.get(&parent_id)?;
+        paint_order.push(
+            parent
+                .children
+                .iter()
+                .position(|child_id| *child_id == current_id)?,
+        );
+        if parent_id.is_nil() {
+            break;
+        }
+
+        // The children of bools and raw SVGs are not painted
+        if parent.hidden() || !parent.is_recursive() || matches!(parent.kind, Kind::Bool(_, _)) {
+            return None;
+        }
+        if parent.clips_children() {
+            let clip = parent.to_world_path().unwrap_or_else(|| parent.hit_path());
+            region = region.op(&clip, skia::PathOp::Intersect)?;
+        }
+        // Masks are not painted, but the rest of the children are only painted inside them
+        if let Some(mask_id) = parent.mask_id() {
+            if mask_id == current_id {
+                return None;
+            }
+            if let Some(mask) = shapes.get(&mask_id) {
+                region = region.op(&mask.hit_path(), skia::PathOp::Intersect)?;
+            }
+        }
+        current_id = parent_id;
+    }
+
+    if region.is_empty() {
+        return None;
+    }
+    paint_order.reverse();
+    Some((region, paint_order))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::{compute_bool_path, BoolType, Fill, Path, RawPathData, Stroke};
+
+    fn index_of(shapes: &HashMap<Uuid, Shape>) -> SpatialIndex {
+        let mut index = SpatialIndex::new();
+        index.rebuild(shapes);
+        index
+    }
+
+    fn tree(children: Vec<Shape>) -> HashMap<Uuid, Shape> {
+        let mut root = Shape::new(Uuid::nil());
+        let mut shapes = HashMap::new();
+        for shape in children {
+            root.add_child(shape.id);
+            shapes.insert(shape.id, shape);
+        }
+        shapes.insert(Uuid::nil(), root);
+        shapes
+    }
+
+    fn rect(left: f32, top: f32, right: f32, bottom: f32) -> Shape {
+        let mut shape = Shape::new(Uuid::new_v4());
+        shape.set_selrect(left, top, right, bottom);
+        shape.set_clip(false);
+        shape.add_fill(Fill::Solid(skia::Color::RED));
+        shape
+    }
+
+    #[test]
+    fn points_are_hit_topmost_first() {
+        let (bottom, top) = (rect(0., 0., 100., 100.), rect(50., 50., 150., 150.));
+        let (bottom_id, top_id) = (bottom.id, top.id);
+        let shapes = tree(vec![bottom, top]);
+        let index = index_of(&shapes);
+
+        assert_eq!(
+            shapes_at((75., 75.), &shapes, &index),
+            vec![top_id, bottom_id]
+        );
+        assert_eq!(shapes_at((25., 25.), &shapes, &index), vec![bottom_id]);
+    }
+
+    #[test]
+    fn rounded_corners_and_circles_are_not_hit_out_of_their_outline() {
+        let mut rounded = rect(0., 0., 100., 100.);
+        rounded.set_corners((20., 20., 20., 20.));
+        let mut circle = rect(200., 0., 300., 100.);
+        circle.set_kind(Kind::Circle(circle.selrect));
+        let (rounded_id, circle_id) = (rounded.id, circle.id);
+        let shapes = tree(vec![rounded, circle]);
+        let index = index_of(&shapes);
+
+        assert!(shapes_at((2., 2.), &shapes, &index).is_empty());
+        assert_eq!(shapes_at((10., 50.), &shapes, &index), vec![rounded_id]);
+        assert!(shapes_at((205., 5.), &shapes, &index).is_empty());
+        assert_eq!(shapes_at((250., 50.), &shapes, &index), vec![circle_id]);
+    }
+
+    fn path(segments: &[(u16, f32, f32)]) -> Path {
+        let raw: Vec<RawPathData> = segments
+            .iter()
+            .map(|(command, x, y)| {
+                let mut data = [0u8; 28];
+                data[0..2].copy_from_slice(&command.to_be_bytes());
+                data[20..24].copy_from_slice(&x.to_be_bytes());
+                data[24..28].copy_from_slice(&y.to_be_bytes());
+                RawPathData { data }
+            })
+            .collect();
+        Path::try_from(raw).unwrap()
+    }
+
+    #[test]
+    fn paths_are_hit_according_to_their_fill_rule() {
+        // Two nested squares, so the inner one is a hole with the even-odd rule
+        let mut shape = rect(0., 0., 100., 100.);
+        shape.set_kind(Kind::Path(path(&[
+            (1, 0., 0.),
+            (2, 100., 0.),
+            (2, 100., 100.),
+            (2, 0., 100.),
+            (4, 0., 0.),
+            (1, 25., 25.),
+            (2, 75., 25.),
+            (2, 75., 75.),
+            (2, 25., 75.),
+            (4, 0., 0.),
+        ])));
+        let id = shape.id;
+        let mut shapes = tree(vec![shape]);
+        let index = index_of(&shapes);
+        assert_eq!(shapes_at((50., 50.), &shapes, &index), vec![id]);
+
+        let shape = shapes.get_mut(&id).unwrap();
+        shape
+            .set_path_attr("fill-rule".to_string(), "evenodd".to_string())
+            .unwrap();
+        assert!(shapes_at((50., 50.), &shapes, &index).is_empty());
+        assert_eq!(shapes_at((10., 50.), &shapes, &index), vec![id]);
+    }
+
+    #[test]
+    fn open_paths_are_hit_within_their_stroke_width() {
+        let mut shape = rect(0., 0., 100., 10.);
+        shape.set_kind(Kind::Path(path(&[(1, 0., 0.), (2, 100., 0.)])));
+        shape.add_stroke(Stroke::new_center_stroke(10., 0, 0, 0));
+        let id = shape.id;
+        let shapes = tree(vec![shape]);
+        let index = index_of(&shapes);
+
+        assert_eq!(shapes_at((50., 4.), &shapes, &index), vec![id]);
+        assert!(shapes_at((50., 8.), &shapes, &index).is_empty());
+    }
+
+    #[test]
+    fn transforms_are_applied_to_the_geometry() {
+        let mut shape = rect(0., 0., 100., 100.);
+        // Rotated 45 degrees around its center
+        let (sin, cos) = std::f32::consts::FRAC_PI_4.sin_cos();
+        shape.set_transform(cos, sin, -sin, cos, 0., 0.);
+        let id = shape.id;
+        let shapes = tree(vec![shape]);
+        let index = index_of(&shapes);
+
+        assert!(shapes_at((5., 5.), &shapes, &index).is_empty());
+        assert_eq!(shapes_at((50., -10.), &shapes, &index), vec![id]);
+    }
+
+    #[test]
+    fn frames_clip_the_hit_area_of_their_children() {
+        let mut frame = rect(0., 0., 100., 100.);
+        frame.set_kind(Kind::Frame(frame.selrect, None));
+        frame.set_clip(true);
+        let child = rect(50., 50., 150., 150.);
+        frame.add_child(child.id);
+        let (frame_id, child_id) = (frame.id, child.id);
+        let mut shapes = tree(vec![frame]);
+        shapes.insert(child_id, child);
+        let index = index_of(&shapes);
+
+        assert_eq!(
+            shapes_at((75., 75.), &shapes, &index),
+            vec![child_id, frame_id]
+        );
+        assert!(shapes_at((125., 125.), &shapes, &index).is_empty());
+        assert_eq!(
+            shapes_in_rect(
+                math::Rect::from_xywh(110., 110., 50., 50.),
+                false,
+                &shapes,
+                &index
+            ),
+            Vec::<Uuid>::new()
+        );
+    }
+
+    #[test]
+    fn bools_are_hit_on_their_result_instead_of_their_children() {
+        let mut bool_shape = rect(0., 0., 150., 100.);
+        bool_shape.set_bool_type(BoolType::Difference);
+        let (first, second) = (rect(0., 0., 100., 100.), rect(50., 0., 150., 100.));
+        bool_shape.add_child(first.id);
+        bool_shape.add_child(second.id);
+        let bool_id = bool_shape.id;
+        let mut shapes = tree(vec![bool_shape]);
+        shapes.insert(first.id, first);
+        shapes.insert(second.id, second);
+        // The state stores the result as the path of the bool
+        let path = compute_bool_path(&shapes[&bool_id], &shapes).unwrap();
+        let bool_shape = shapes.get_mut(&bool_id).unwrap();
+        bool_shape.set_kind(Kind::Bool(BoolType::Difference, Path::from(path)));
+        let index = index_of(&shapes);
+
+        assert_eq!(shapes_at((25., 50.), &shapes, &index), vec![bool_id]);
+        assert!(shapes_at((75., 50.), &shapes, &index).is_empty());
+        assert!(shapes_at((125., 50.), &shapes, &index).is_empty());
+    }
+
+    #[test]
+    fn hidden_shapes_and_their_children_are_not_hit() {
+        let mut group = rect(0., 0., 100., 100.);
+        let child = rect(0., 0., 50., 50.);
+        group.add_child(child.id);
+        group.set_hidden(true);
+        let child_id = child.id;
+        let mut shapes = tree(vec![group]);
+        shapes.insert(child_id, child);
+        let index = index_of(&shapes);
+
+        assert!(shapes_at((25., 25.), &shapes, &index).is_empty());
+    }
+
+    #[test]
+    fn rects_select_shapes_partially_or_fully_inside() {
+        let (inside, crossing) = (rect(10., 10., 20., 20.), rect(40., 40., 80., 80.));
+        let (inside_id, crossing_id) = (inside.id, crossing.id);
+        let shapes = tree(vec![inside, crossing]);
+        let index = index_of(&shapes);
+        let selection = math::Rect::from_xywh(0., 0., 50., 50.);
+
+        assert_eq!(
+            shapes_in_rect(selection, false, &shapes, &index),
+            vec![crossing_id, inside_id]
+        );
+        assert_eq!(
+            shapes_in_rect(selection, true, &shapes, &index),
+            vec![inside_id]
+        );
+    }
+}
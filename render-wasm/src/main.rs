@@ -1,27 +1,46 @@
 use skia_safe as skia;
 
 mod debug;
+mod error;
+mod hit_test;
 mod math;
 mod mem;
 mod render;
 mod shapes;
+mod spatial;
 mod state;
 mod utils;
 mod view;
 
-use crate::shapes::{BoolType, Kind, Path};
+use crate::error::Error;
+use crate::shapes::{BoolType, Group, Kind, Path, Shape};
 
 use crate::state::State;
-use crate::utils::uuid_from_u32_quartet;
+use crate::utils::{
+    uuid_from_u32_quartet, uuid_from_u32_quartet_bytes, uuids_to_u32_quartet_bytes,
+};
 
 static mut STATE: Option<Box<State>> = None;
 
+fn state() -> error::Result<&'static mut State<'static>> {
+    unsafe { STATE.as_deref_mut() }
+        .ok_or_else(|| Error::invalid_state("The state has not been initialized"))
+}
+
+fn current_shape() -> error::Result<&'static mut Shape> {
+    state()?
+        .current_shape()
+        .ok_or_else(|| Error::invalid_state("There is no current shape"))
+}
+
+#[cfg(target_os = "emscripten")]
 extern "C" {
     fn emscripten_GetProcAddress(
         name: *const ::std::os::raw::c_char,
     ) -> *const ::std::os::raw::c_void;
 }
 
+#[cfg(target_os = "emscripten")]
 fn init_gl() {
     unsafe {
         gl::load_with(|addr| {
@@ -33,202 +52,352 @@ fn init_gl() {
 
 /// This is called from JS after the WebGL context has been created.
 #[no_mangle]
-pub extern "C" fn init(width: i32, height: i32) {
-    let state_box = Box::new(State::new(width, height, 2048));
-    unsafe {
-        STATE = Some(state_box);
+pub extern "C" fn init(width: i32, height: i32) -> u8 {
+    error::status(
+        State::new(width, height, 2048)
+            .map(|state| unsafe { STATE = Some(Box::new(state)) })
+            .map_err(Error::render),
+    )
+}
+
+/// Same as [init], but rendering on the CPU. It does not need a WebGL context.
+#[no_mangle]
+pub extern "C" fn init_headless(width: i32, height: i32) -> u8 {
+    error::status(
+        State::new_raster(width, height, 2048)
+            .map(|state| unsafe { STATE = Some(Box::new(state)) })
+            .map_err(Error::render),
+    )
+}
+
+/// Writes the last error reported by an exported function to a buffer, as its
+/// [error::ErrorKind] (`u8`) followed by its UTF-8 message, and clears it. Nothing follows the
+/// length prefix when there is no error. It returns `0` if the buffer cannot be allocated.
+#[no_mangle]
+pub extern "C" fn get_last_error() -> u32 {
+    let bytes = error::last_error().map(Error::to_bytes).unwrap_or_default();
+    match mem::write_bytes(bytes) {
+        Ok(handle) => {
+            error::clear_last_error();
+            handle.into()
+        }
+        Err(_) => 0,
     }
 }
 
 #[no_mangle]
 pub extern "C" fn clean_up() {
     unsafe { STATE = None }
-    mem::free_bytes();
+    mem::free_all();
 }
 
 #[no_mangle]
-pub extern "C" fn set_render_options(debug: u32, dpr: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    let render_state = state.render_state();
+pub extern "C" fn set_render_options(debug: u32, dpr: f32) -> u8 {
+    error::status(state().and_then(|state| {
+        let render_state = state.render_state();
 
-    render_state.set_debug_flags(debug);
-    render_state.set_dpr(dpr);
+        render_state.set_debug_flags(debug);
+        render_state.set_dpr(dpr).map_err(Error::render)
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn set_canvas_background(raw_color: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-
+pub extern "C" fn set_canvas_background(raw_color: u32) -> u8 {
     let color = skia::Color::new(raw_color);
-    state.set_background_color(color);
+    error::status(state().map(|state| state.set_background_color(color)))
 }
 
+/// Renders the viewbox, painting again only the area of the shapes changed since the last
+/// frame on top of the cached tiles.
 #[no_mangle]
-pub unsafe extern "C" fn render() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    state.render_all(true);
+pub extern "C" fn render() -> u8 {
+    error::status(state().and_then(|state| state.render_all(false).map_err(Error::render)))
 }
 
+/// Renders the tiles of the viewbox that are missing, reusing the cached ones.
 #[no_mangle]
-pub unsafe extern "C" fn render_without_cache() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    state.render_all(false);
+pub extern "C" fn render_without_cache() -> u8 {
+    error::status(state().and_then(|state| state.render_all(false).map_err(Error::render)))
 }
 
+/// Renders the whole viewbox again, discarding the cached tiles of every scale. Meant for
+/// changes that can affect any shape, like a font being loaded.
 #[no_mangle]
-pub unsafe extern "C" fn zoom() {
-    let state: &mut Box<State<'_>> =
-        unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    state.zoom();
+pub extern "C" fn invalidate_tiles() -> u8 {
+    error::status(state().and_then(|state| state.render_all(true).map_err(Error::render)))
 }
 
+/// Renders a shape and its children at the given scale and returns the handle of a buffer with
+/// the encoded image, prefixed by its length (see [mem::write_bytes]).
 #[no_mangle]
-pub unsafe extern "C" fn pan() {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    state.pan();
+pub extern "C" fn export_shape(
+    a: u32,
+    b: u32,
+    c: u32,
+    d: u32,
+    scale: f32,
+    format: u8,
+    quality: u32,
+) -> u32 {
+    let id = uuid_from_u32_quartet(a, b, c, d);
+    error::handle_status(state().and_then(|state| {
+        let bytes = state
+            .export_shape(id, scale, format.into(), quality)
+            .map_err(Error::render)?;
+        mem::write_bytes(bytes)
+    }))
+}
+
+/// Renders the frames whose ids are in the given buffer (16 bytes each) as the pages of a PDF
+/// document, and returns it like [export_shape].
+#[no_mangle]
+pub extern "C" fn export_pdf(handle: u32, count: u32) -> u32 {
+    let bytes = mem::bytes(handle);
+    error::handle_status(bytes.and_then(|bytes| {
+        if bytes.len() < count as usize * 16 {
+            return Err(Error::invalid_input(format!(
+                "Expected {} frame ids, the buffer has {} bytes",
+                count,
+                bytes.len()
+            )));
+        }
+        let ids: Vec<_> = bytes
+            .chunks_exact(16)
+            .take(count as usize)
+            .map(uuid_from_u32_quartet_bytes)
+            .collect();
+
+        let pdf = state()?.export_pdf(&ids).map_err(Error::render)?;
+        mem::write_bytes(pdf)
+    }))
 }
 
+/// Renders a shape and its children as an UTF-8 SVG document, and returns it like
+/// [export_shape].
 #[no_mangle]
-pub extern "C" fn reset_canvas() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    state.render_state().reset_canvas();
+pub extern "C" fn export_svg(a: u32, b: u32, c: u32, d: u32, text_to_paths: bool) -> u32 {
+    let id = uuid_from_u32_quartet(a, b, c, d);
+    error::handle_status(state().and_then(|state| {
+        let svg = state.export_svg(id, text_to_paths).map_err(Error::render)?;
+        mem::write_bytes(svg.into_bytes())
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn resize_viewbox(width: i32, height: i32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    state.resize(width, height);
+pub extern "C" fn zoom() -> u8 {
+    error::status(state().and_then(|state| state.zoom().map_err(Error::render)))
 }
 
 #[no_mangle]
-pub extern "C" fn set_view(zoom: f32, x: f32, y: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    state.render_state().viewbox.set_all(zoom, x, y);
+pub extern "C" fn pan() -> u8 {
+    error::status(state().and_then(|state| state.pan().map_err(Error::render)))
 }
 
 #[no_mangle]
-pub extern "C" fn set_view_zoom(zoom: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    state.render_state().viewbox.set_zoom(zoom);
+pub extern "C" fn reset_canvas() -> u8 {
+    error::status(state().map(|state| state.render_state().reset_canvas()))
 }
 
 #[no_mangle]
-pub extern "C" fn set_view_xy(x: f32, y: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    state.render_state().viewbox.set_pan_xy(x, y);
+pub extern "C" fn resize_viewbox(width: i32, height: i32) -> u8 {
+    error::status(state().and_then(|state| state.resize(width, height).map_err(Error::render)))
 }
 
 #[no_mangle]
-pub extern "C" fn use_shape(a: u32, b: u32, c: u32, d: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+pub extern "C" fn set_view(zoom: f32, x: f32, y: f32) -> u8 {
+    error::status(state().map(|state| state.render_state().viewbox.set_all(zoom, x, y)))
+}
+
+#[no_mangle]
+pub extern "C" fn set_view_zoom(zoom: f32) -> u8 {
+    error::status(state().map(|state| state.render_state().viewbox.set_zoom(zoom)))
+}
+
+#[no_mangle]
+pub extern "C" fn set_view_xy(x: f32, y: f32) -> u8 {
+    error::status(state().map(|state| state.render_state().viewbox.set_pan_xy(x, y)))
+}
+
+/// Returns a buffer with the area painted by the shape and its children in world coordinates,
+/// as four `f32` (left, top, right, bottom) prefixed by their length in bytes. The length is
+/// `0` when the shape does not exist or is hidden.
+#[no_mangle]
+pub extern "C" fn get_shape_visual_bounds(a: u32, b: u32, c: u32, d: u32) -> u32 {
     let id = uuid_from_u32_quartet(a, b, c, d);
-    state.use_shape(id);
+    error::handle_status(state().and_then(|state| {
+        let bytes = match state.shape_visual_bounds(id) {
+            Some(rect) => [rect.left, rect.top, rect.right, rect.bottom]
+                .iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect(),
+            None => vec![],
+        };
+        mem::write_bytes(bytes)
+    }))
+}
+
+/// Returns a buffer with the ids (16 bytes each) of the visible shapes under the given point in
+/// world coordinates, topmost first, prefixed by their length in bytes (see [mem::write_bytes]).
+#[no_mangle]
+pub extern "C" fn get_shapes_at(x: f32, y: f32) -> u32 {
+    error::handle_status(state().and_then(|state| {
+        let ids = state.shapes_at(x, y);
+        mem::write_bytes(uuids_to_u32_quartet_bytes(&ids))
+    }))
+}
+
+/// Like [get_shapes_at], but returns the shapes inside the given rect. When `contained` is not
+/// set, the shapes partially inside it are returned too.
+#[no_mangle]
+pub extern "C" fn get_shapes_in_rect(
+    left: f32,
+    top: f32,
+    right: f32,
+    bottom: f32,
+    contained: bool,
+) -> u32 {
+    let rect = math::Rect::from_ltrb(left, top, right, bottom);
+    error::handle_status(state().and_then(|state| {
+        let ids = state.shapes_in_rect(rect, contained);
+        mem::write_bytes(uuids_to_u32_quartet_bytes(&ids))
+    }))
+}
+
+#[no_mangle]
+pub extern "C" fn use_shape(a: u32, b: u32, c: u32, d: u32) -> u8 {
+    let id = uuid_from_u32_quartet(a, b, c, d);
+    error::status(state().map(|state| state.use_shape(id)))
 }
 
+/// Reads `count` shape records from the given buffer, and replaces (or adds) those shapes with
+/// all their properties in a single call. See the layout in `docs/serialization.md`.
 #[no_mangle]
-pub unsafe extern "C" fn set_shape_kind_circle() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+pub extern "C" fn set_shapes(handle: u32, count: u32) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let shapes = shapes::read_shapes(&bytes, count as usize).map_err(Error::invalid_input)?;
+        state()?.set_shapes(shapes);
+        Ok(())
+    }))
+}
 
-    if let Some(shape) = state.current_shape() {
-        shape.set_kind(Kind::Circle(math::Rect::new_empty()));
-    }
+/// Removes a shape, along with the children that have not been moved to another shape.
+#[no_mangle]
+pub extern "C" fn delete_shape(a: u32, b: u32, c: u32, d: u32) -> u8 {
+    let id = uuid_from_u32_quartet(a, b, c, d);
+    error::status(state().map(|state| state.delete_shape(id)))
 }
 
+/// Removes a shape and all its descendants.
 #[no_mangle]
-pub unsafe extern "C" fn set_shape_kind_rect() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+pub extern "C" fn delete_shapes_subtree(a: u32, b: u32, c: u32, d: u32) -> u8 {
+    let id = uuid_from_u32_quartet(a, b, c, d);
+    error::status(state().map(|state| state.delete_shapes_subtree(id)))
+}
 
-    if let Some(shape) = state.current_shape() {
-        match shape.kind() {
-            Kind::Rect(_, _) => {}
-            _ => shape.set_kind(Kind::Rect(math::Rect::new_empty(), None)),
-        }
-    }
+#[no_mangle]
+pub extern "C" fn set_shape_kind_circle() -> u8 {
+    error::status(
+        current_shape().map(|shape| shape.set_kind(Kind::Circle(math::Rect::new_empty()))),
+    )
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn set_shape_kind_path() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_kind(Kind::Path(Path::default()));
-    }
+pub extern "C" fn set_shape_kind_rect() -> u8 {
+    error::status(current_shape().map(|shape| match shape.kind() {
+        Kind::Rect(_, _) => {}
+        _ => shape.set_kind(Kind::Rect(math::Rect::new_empty(), None)),
+    }))
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn set_shape_kind_bool() {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        match shape.kind() {
-            Kind::Bool(_, _) => {}
-            _ => shape.set_kind(Kind::Bool(BoolType::default(), Path::default())),
-        }
-    }
+pub extern "C" fn set_shape_kind_frame() -> u8 {
+    error::status(current_shape().map(|shape| match shape.kind() {
+        Kind::Frame(_, _) => {}
+        // Rects become frames keeping their corners
+        Kind::Rect(rect, corners) => shape.set_kind(Kind::Frame(rect, corners)),
+        _ => shape.set_kind(Kind::Frame(shape.selrect, None)),
+    }))
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn set_shape_bool_type(raw_bool_type: u8) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_bool_type(BoolType::from(raw_bool_type))
-    }
+pub extern "C" fn set_shape_kind_group() -> u8 {
+    error::status(current_shape().map(|shape| match shape.kind() {
+        Kind::Group(_) => {}
+        _ => shape.set_kind(Kind::Group(Group::default())),
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_selrect(left: f32, top: f32, right: f32, bottom: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_selrect(left, top, right, bottom);
-    }
+pub extern "C" fn set_shape_masked_group(masked: bool) -> u8 {
+    error::status(
+        current_shape().and_then(|shape| shape.set_masked(masked).map_err(Error::invalid_state)),
+    )
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn set_shape_clip_content(clip_content: bool) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_clip(clip_content);
-    }
+pub extern "C" fn set_shape_kind_path() -> u8 {
+    error::status(current_shape().map(|shape| shape.set_kind(Kind::Path(Path::default()))))
 }
 
 #[no_mangle]
-pub unsafe extern "C" fn set_shape_rotation(rotation: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_rotation(rotation);
-    }
+pub extern "C" fn set_shape_kind_bool() -> u8 {
+    error::status(current_shape().map(|shape| match shape.kind() {
+        Kind::Bool(_, _) => {}
+        _ => shape.set_kind(Kind::Bool(BoolType::default(), Path::default())),
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_transform(a: f32, b: f32, c: f32, d: f32, e: f32, f: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_transform(a, b, c, d, e, f);
-    }
+pub extern "C" fn set_shape_kind_text() -> u8 {
+    error::status(current_shape().map(|shape| match shape.kind() {
+        Kind::Text(_) => {}
+        _ => shape.clear_text_content(),
+    }))
+}
+
+#[no_mangle]
+pub extern "C" fn set_shape_bool_type(raw_bool_type: u8) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_bool_type(BoolType::from(raw_bool_type))))
+}
+
+#[no_mangle]
+pub extern "C" fn set_shape_selrect(left: f32, top: f32, right: f32, bottom: f32) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_selrect(left, top, right, bottom)))
+}
+
+#[no_mangle]
+pub extern "C" fn set_shape_clip_content(clip_content: bool) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_clip(clip_content)))
 }
 
 #[no_mangle]
-pub extern "C" fn add_shape_child(a: u32, b: u32, c: u32, d: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+pub extern "C" fn set_shape_rotation(rotation: f32) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_rotation(rotation)))
+}
+
+#[no_mangle]
+pub extern "C" fn set_shape_transform(a: f32, b: f32, c: f32, d: f32, e: f32, f: f32) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_transform(a, b, c, d, e, f)))
+}
+
+#[no_mangle]
+pub extern "C" fn add_shape_child(a: u32, b: u32, c: u32, d: u32) -> u8 {
     let id = uuid_from_u32_quartet(a, b, c, d);
-    if let Some(shape) = state.current_shape() {
-        shape.add_child(id);
-    }
+    error::status(state().map(|state| state.add_shape_child(id)))
 }
 
 #[no_mangle]
-pub extern "C" fn clear_shape_children() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.clear_children();
-    }
+pub extern "C" fn clear_shape_children() -> u8 {
+    error::status(state().map(|state| state.clear_shape_children()))
 }
 
 #[no_mangle]
-pub extern "C" fn add_shape_solid_fill(raw_color: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+pub extern "C" fn add_shape_solid_fill(raw_color: u32) -> u8 {
+    error::status(current_shape().map(|shape| {
         let color = skia::Color::new(raw_color);
         shape.add_fill(shapes::Fill::Solid(color));
-    }
+    }))
 }
 
 #[no_mangle]
@@ -238,15 +407,14 @@ pub extern "C" fn add_shape_linear_fill(
     end_x: f32,
     end_y: f32,
     opacity: f32,
-) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+) -> u8 {
+    error::status(current_shape().map(|shape| {
         shape.add_fill(shapes::Fill::new_linear_gradient(
             (start_x, start_y),
             (end_x, end_y),
             opacity,
-        ))
-    }
+        ));
+    }))
 }
 
 #[no_mangle]
@@ -257,82 +425,129 @@ pub extern "C" fn add_shape_radial_fill(
     end_y: f32,
     opacity: f32,
     width: f32,
-) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+) -> u8 {
+    error::status(current_shape().map(|shape| {
         shape.add_fill(shapes::Fill::new_radial_gradient(
             (start_x, start_y),
             (end_x, end_y),
             opacity,
             width,
-        ))
-    }
+        ));
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn add_shape_fill_stops(ptr: *mut shapes::RawStopData, n_stops: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-
-    if let Some(shape) = state.current_shape() {
-        let len = n_stops as usize;
+pub extern "C" fn add_shape_angular_fill(
+    start_x: f32,
+    start_y: f32,
+    end_x: f32,
+    end_y: f32,
+    opacity: f32,
+) -> u8 {
+    error::status(current_shape().map(|shape| {
+        shape.add_fill(shapes::Fill::new_angular_gradient(
+            (start_x, start_y),
+            (end_x, end_y),
+            opacity,
+        ));
+    }))
+}
 
-        unsafe {
-            let buffer = Vec::<shapes::RawStopData>::from_raw_parts(ptr, len, len);
-            shape
-                .add_fill_gradient_stops(buffer)
-                .expect("could not add gradient stops");
-            mem::free_bytes();
+#[no_mangle]
+pub extern "C" fn add_shape_diamond_fill(
+    start_x: f32,
+    start_y: f32,
+    end_x: f32,
+    end_y: f32,
+    opacity: f32,
+    width: f32,
+) -> u8 {
+    error::status(current_shape().and_then(|shape| {
+        let fill =
+            shapes::Fill::new_diamond_gradient((start_x, start_y), (end_x, end_y), opacity, width)
+                .map_err(Error::render)?;
+        shape.add_fill(fill);
+        Ok(())
+    }))
+}
+
+/// Adds the gradient stops in the given buffer (see `RawStopData`) to the last fill.
+#[no_mangle]
+pub extern "C" fn add_shape_fill_stops(handle: u32) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let stops = shapes::RawStopData::from_bytes(&bytes).map_err(Error::invalid_input)?;
+        current_shape()?
+            .add_fill_gradient_stops(stops)
+            .map_err(Error::invalid_state)
+    }))
+}
+
+#[no_mangle]
+pub extern "C" fn store_font(handle: u32, family_name_size: u32, font_size: u32) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let (font_size, family_name_size) = (font_size as usize, family_name_size as usize);
+        if bytes.len() < font_size + family_name_size {
+            return Err(Error::invalid_input(format!(
+                "Expected {} bytes of font data, the buffer has {}",
+                font_size + family_name_size,
+                bytes.len()
+            )));
         }
-    }
+        let (font_bytes, family_name) = bytes.split_at(font_size);
+        let family_name = String::from_utf8(family_name[..family_name_size].to_vec())
+            .map_err(|err| Error::invalid_input(err.to_string()))?;
+
+        state()?
+            .add_font(family_name, font_bytes)
+            .map_err(Error::invalid_input)
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn store_font(family_name_size: u32, font_size: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    unsafe {
-        let font_bytes =
-            Vec::<u8>::from_raw_parts(mem::buffer_ptr(), font_size as usize, font_size as usize);
-        let family_name = String::from_raw_parts(
-            mem::buffer_ptr().add(font_size as usize),
-            family_name_size as usize,
-            family_name_size as usize,
-        );
-        match state.render_state().add_font(family_name, &font_bytes) {
-            Err(msg) => {
-                eprintln!("{}", msg);
-            }
-            _ => {}
-        }
-        mem::free_bytes();
-    }
+pub extern "C" fn store_image(handle: u32, a: u32, b: u32, c: u32, d: u32, size: u32) -> u8 {
+    let id = uuid_from_u32_quartet(a, b, c, d);
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let image_bytes = bytes.get(..size as usize).ok_or_else(|| {
+            Error::invalid_input(format!(
+                "Expected {} bytes of image data, the buffer has {}",
+                size,
+                bytes.len()
+            ))
+        })?;
+        state()?
+            .add_image(id, image_bytes)
+            .map_err(Error::invalid_input)
+    }))
 }
 
+/// Removes an image, which has to be stored again to render the shapes using it.
 #[no_mangle]
-pub extern "C" fn store_image(a: u32, b: u32, c: u32, d: u32, size: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+pub extern "C" fn release_image(a: u32, b: u32, c: u32, d: u32) -> u8 {
     let id = uuid_from_u32_quartet(a, b, c, d);
+    error::status(state().map(|state| state.release_image(id)))
+}
 
-    unsafe {
-        let image_bytes =
-            Vec::<u8>::from_raw_parts(mem::buffer_ptr(), size as usize, size as usize);
-        match state.render_state().add_image(id, &image_bytes) {
-            Err(msg) => {
-                eprintln!("{}", msg);
-            }
-            _ => {}
-        }
-        mem::free_bytes();
-    }
+/// Sets the bytes the decoded images can take before the least recently rendered ones are
+/// evicted.
+#[no_mangle]
+pub extern "C" fn set_image_budget(bytes: u32) -> u8 {
+    error::status(state().map(|state| state.render_state().set_image_budget(bytes as usize)))
 }
 
+/// Returns whether the image is stored (see [error::query_status]). Images are evicted when
+/// they exceed the budget (see [set_image_budget]), so JS has to check it and store them again
+/// before rendering.
 #[no_mangle]
-pub extern "C" fn is_image_cached(a: u32, b: u32, c: u32, d: u32) -> bool {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+pub extern "C" fn is_image_cached(a: u32, b: u32, c: u32, d: u32) -> u8 {
     let id = uuid_from_u32_quartet(a, b, c, d);
-    state.render_state().has_image(&id)
+    error::query_status(state().map(|state| state.render_state().has_image(&id)))
 }
 
 #[no_mangle]
+#[allow(clippy::too_many_arguments)]
 pub extern "C" fn add_shape_image_fill(
     a: u32,
     b: u32,
@@ -341,128 +556,113 @@ pub extern "C" fn add_shape_image_fill(
     alpha: f32,
     width: i32,
     height: i32,
-) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+    scale_mode: u8,
+    tile_scale: f32,
+) -> u8 {
     let id = uuid_from_u32_quartet(a, b, c, d);
-    if let Some(shape) = state.current_shape() {
+    error::status(current_shape().map(|shape| {
         shape.add_fill(shapes::Fill::new_image_fill(
             id,
             (alpha * 0xff as f32).floor() as u8,
             (width, height),
+            scale_mode.into(),
+            tile_scale,
         ));
-    }
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn clear_shape_fills() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.clear_fills();
-    }
+pub extern "C" fn set_shape_image_fill_crop(x: f32, y: f32, width: f32, height: f32) -> u8 {
+    error::status(current_shape().and_then(|shape| {
+        shape
+            .set_image_fill_crop((x, y, width, height))
+            .map_err(Error::invalid_state)
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_svg_raw_content() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        let bytes = mem::bytes();
-        let svg_raw_content = String::from_utf8(bytes)
-            .unwrap()
-            .trim_end_matches('\0')
-            .to_string();
-        shape
-            .set_svg_raw_content(svg_raw_content)
-            .expect("Failed to set svg raw content");
-    }
+pub extern "C" fn clear_shape_fills() -> u8 {
+    error::status(current_shape().map(|shape| shape.clear_fills()))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_blend_mode(mode: i32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_blend_mode(render::BlendMode::from(mode));
-    }
+pub extern "C" fn set_shape_svg_raw_content(handle: u32) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let svg_raw_content =
+            String::from_utf8(bytes).map_err(|err| Error::invalid_input(err.to_string()))?;
+        current_shape()?
+            .set_svg_raw_content(svg_raw_content.trim_end_matches('\0').to_string())
+            .map_err(Error::invalid_state)
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_opacity(opacity: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_opacity(opacity);
-    }
+pub extern "C" fn set_shape_blend_mode(mode: i32) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_blend_mode(render::BlendMode::from(mode))))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_hidden(hidden: bool) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_hidden(hidden);
-    }
+pub extern "C" fn set_shape_opacity(opacity: f32) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_opacity(opacity)))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_blur(blur_type: u8, hidden: bool, value: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_blur(blur_type, hidden, value);
-    }
+pub extern "C" fn set_shape_hidden(hidden: bool) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_hidden(hidden)))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_path_content() {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+pub extern "C" fn set_shape_blur(blur_type: u8, hidden: bool, value: f32) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_blur(blur_type, hidden, value)))
+}
 
-    if let Some(shape) = state.current_shape() {
-        let bytes = mem::bytes();
-        let raw_segments = bytes
-            .chunks(size_of::<shapes::RawPathData>())
-            .map(|data| shapes::RawPathData {
-                data: data.try_into().unwrap(),
-            })
-            .collect();
-        shape.set_path_segments(raw_segments).unwrap();
-    }
+#[no_mangle]
+pub extern "C" fn set_shape_path_content(handle: u32) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let raw_segments = shapes::RawPathData::from_bytes(&bytes).map_err(Error::invalid_input)?;
+        current_shape()?
+            .set_path_segments(raw_segments)
+            .map_err(Error::invalid_input)
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn add_shape_center_stroke(width: f32, style: u8, cap_start: u8, cap_end: u8) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+pub extern "C" fn add_shape_center_stroke(width: f32, style: u8, cap_start: u8, cap_end: u8) -> u8 {
+    error::status(current_shape().map(|shape| {
         shape.add_stroke(shapes::Stroke::new_center_stroke(
             width, style, cap_start, cap_end,
         ));
-    }
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn add_shape_inner_stroke(width: f32, style: u8, cap_start: u8, cap_end: u8) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+pub extern "C" fn add_shape_inner_stroke(width: f32, style: u8, cap_start: u8, cap_end: u8) -> u8 {
+    error::status(current_shape().map(|shape| {
         shape.add_stroke(shapes::Stroke::new_inner_stroke(
             width, style, cap_start, cap_end,
-        ))
-    }
+        ));
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn add_shape_outer_stroke(width: f32, style: u8, cap_start: u8, cap_end: u8) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+pub extern "C" fn add_shape_outer_stroke(width: f32, style: u8, cap_start: u8, cap_end: u8) -> u8 {
+    error::status(current_shape().map(|shape| {
         shape.add_stroke(shapes::Stroke::new_outer_stroke(
             width, style, cap_start, cap_end,
-        ))
-    }
+        ));
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn add_shape_stroke_solid_fill(raw_color: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+pub extern "C" fn add_shape_stroke_solid_fill(raw_color: u32) -> u8 {
+    error::status(current_shape().and_then(|shape| {
         let color = skia::Color::new(raw_color);
         shape
             .set_stroke_fill(shapes::Fill::Solid(color))
-            .expect("could not add stroke solid fill");
-    }
+            .map_err(Error::invalid_state)
+    }))
 }
 
 #[no_mangle]
@@ -472,17 +672,16 @@ pub extern "C" fn add_shape_stroke_linear_fill(
     end_x: f32,
     end_y: f32,
     opacity: f32,
-) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+) -> u8 {
+    error::status(current_shape().and_then(|shape| {
         shape
             .set_stroke_fill(shapes::Fill::new_linear_gradient(
                 (start_x, start_y),
                 (end_x, end_y),
                 opacity,
             ))
-            .expect("could not add stroke linear fill");
-    }
+            .map_err(Error::invalid_state)
+    }))
 }
 
 #[no_mangle]
@@ -493,9 +692,8 @@ pub extern "C" fn add_shape_stroke_radial_fill(
     end_y: f32,
     opacity: f32,
     width: f32,
-) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+) -> u8 {
+    error::status(current_shape().and_then(|shape| {
         shape
             .set_stroke_fill(shapes::Fill::new_radial_gradient(
                 (start_x, start_y),
@@ -503,25 +701,69 @@ pub extern "C" fn add_shape_stroke_radial_fill(
                 opacity,
                 width,
             ))
-            .expect("could not add stroke radial fill");
-    }
+            .map_err(Error::invalid_state)
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn add_shape_stroke_stops(ptr: *mut shapes::RawStopData, n_stops: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
+pub extern "C" fn add_shape_stroke_angular_fill(
+    start_x: f32,
+    start_y: f32,
+    end_x: f32,
+    end_y: f32,
+    opacity: f32,
+) -> u8 {
+    error::status(current_shape().and_then(|shape| {
+        shape
+            .set_stroke_fill(shapes::Fill::new_angular_gradient(
+                (start_x, start_y),
+                (end_x, end_y),
+                opacity,
+            ))
+            .map_err(Error::invalid_state)
+    }))
+}
 
-    if let Some(shape) = state.current_shape() {
-        let len = n_stops as usize;
+#[no_mangle]
+pub extern "C" fn add_shape_stroke_diamond_fill(
+    start_x: f32,
+    start_y: f32,
+    end_x: f32,
+    end_y: f32,
+    opacity: f32,
+    width: f32,
+) -> u8 {
+    error::status(current_shape().and_then(|shape| {
+        let fill =
+            shapes::Fill::new_diamond_gradient((start_x, start_y), (end_x, end_y), opacity, width)
+                .map_err(Error::render)?;
+        shape.set_stroke_fill(fill).map_err(Error::invalid_state)
+    }))
+}
 
-        unsafe {
-            let buffer = Vec::<shapes::RawStopData>::from_raw_parts(ptr, len, len);
-            shape
-                .add_stroke_gradient_stops(buffer)
-                .expect("could not add gradient stops");
-            mem::free_bytes();
-        }
-    }
+/// Adds the gradient stops in the given buffer to the fill of the last stroke.
+#[no_mangle]
+pub extern "C" fn add_shape_stroke_stops(handle: u32) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let stops = shapes::RawStopData::from_bytes(&bytes).map_err(Error::invalid_input)?;
+        current_shape()?
+            .add_stroke_gradient_stops(stops)
+            .map_err(Error::invalid_state)
+    }))
+}
+
+/// Sets the dash pattern of the last stroke to the intervals in the given buffer, starting at
+/// `offset`. An empty buffer goes back to the pattern of its style.
+#[no_mangle]
+pub extern "C" fn set_shape_stroke_dash(handle: u32, offset: f32) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let dash = shapes::Dash::from_bytes(&bytes, offset).map_err(Error::invalid_input)?;
+        current_shape()?
+            .set_stroke_dash(dash)
+            .map_err(Error::invalid_state)
+    }))
 }
 
 // Extracts a string from the bytes slice until the next null byte (0) and returns the result as a `String`.
@@ -532,8 +774,7 @@ fn extract_string(start: &mut usize, bytes: &[u8]) -> String {
             let end = *start + pos;
             let slice = &bytes[*start..end];
             *start = end + 1; // Move the `start` pointer past the null byte
-                              // Call to unsafe function within an unsafe block
-            unsafe { String::from_utf8_unchecked(slice.to_vec()) }
+            String::from_utf8_lossy(slice).into_owned()
         }
         None => {
             *start = bytes.len(); // Move `start` to the end if no null byte is found
@@ -543,6 +784,7 @@ fn extract_string(start: &mut usize, bytes: &[u8]) -> String {
 }
 
 #[no_mangle]
+#[allow(clippy::too_many_arguments)]
 pub extern "C" fn add_shape_image_stroke(
     a: u32,
     b: u32,
@@ -551,49 +793,119 @@ pub extern "C" fn add_shape_image_stroke(
     alpha: f32,
     width: i32,
     height: i32,
-) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
+    scale_mode: u8,
+    tile_scale: f32,
+) -> u8 {
     let id = uuid_from_u32_quartet(a, b, c, d);
-    if let Some(shape) = state.current_shape() {
+    error::status(current_shape().and_then(|shape| {
         shape
             .set_stroke_fill(shapes::Fill::new_image_fill(
                 id,
                 (alpha * 0xff as f32).floor() as u8,
                 (width, height),
+                scale_mode.into(),
+                tile_scale,
             ))
-            .expect("could not add stroke image fill");
-    }
+            .map_err(Error::invalid_state)
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn clear_shape_strokes() {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.clear_strokes();
-    }
+pub extern "C" fn set_shape_image_stroke_crop(x: f32, y: f32, width: f32, height: f32) -> u8 {
+    error::status(current_shape().and_then(|shape| {
+        shape
+            .set_image_stroke_crop((x, y, width, height))
+            .map_err(Error::invalid_state)
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_corners(r1: f32, r2: f32, r3: f32, r4: f32) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.set_corners((r1, r2, r3, r4));
-    }
+pub extern "C" fn clear_shape_strokes() -> u8 {
+    error::status(current_shape().map(|shape| shape.clear_strokes()))
 }
 
 #[no_mangle]
-pub extern "C" fn set_shape_path_attrs(num_attrs: u32) {
-    let state = unsafe { STATE.as_mut() }.expect("Got an invalid state pointer");
+pub extern "C" fn set_shape_corners(r1: f32, r2: f32, r3: f32, r4: f32) -> u8 {
+    error::status(current_shape().map(|shape| shape.set_corners((r1, r2, r3, r4))))
+}
 
-    if let Some(shape) = state.current_shape() {
-        let bytes = mem::bytes();
+#[no_mangle]
+pub extern "C" fn set_shape_path_attrs(handle: u32, num_attrs: u32) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let shape = current_shape()?;
         let mut start = 0;
         for _ in 0..num_attrs {
             let name = extract_string(&mut start, &bytes);
             let value = extract_string(&mut start, &bytes);
-            shape.set_path_attr(name, value);
+            shape
+                .set_path_attr(name, value)
+                .map_err(Error::invalid_state)?;
         }
-    }
+        Ok(())
+    }))
+}
+
+#[no_mangle]
+pub extern "C" fn clear_shape_text_content() -> u8 {
+    error::status(current_shape().map(|shape| shape.clear_text_content()))
+}
+
+#[no_mangle]
+pub extern "C" fn add_text_paragraph_set() -> u8 {
+    error::status(
+        current_shape()
+            .and_then(|shape| shape.add_text_paragraph_set().map_err(Error::invalid_state)),
+    )
+}
+
+#[no_mangle]
+pub extern "C" fn add_text_paragraph(text_align: u8) -> u8 {
+    error::status(current_shape().and_then(|shape| {
+        let paragraph = shapes::Paragraph::new(shapes::TextAlign::from(text_align));
+        shape
+            .add_text_paragraph(paragraph)
+            .map_err(Error::invalid_state)
+    }))
+}
+
+// The text and the font family of the leaf are read from the given buffer,
+// as two null-terminated strings.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn add_text_leaf(
+    handle: u32,
+    font_weight: i32,
+    font_style: u8,
+    font_size: f32,
+    line_height: f32,
+    letter_spacing: f32,
+    decoration: u8,
+    transform: u8,
+    raw_color: u32,
+) -> u8 {
+    let bytes = mem::bytes(handle);
+    error::status(bytes.and_then(|bytes| {
+        let mut start = 0;
+        let text = extract_string(&mut start, &bytes);
+        let font_family = extract_string(&mut start, &bytes);
+
+        let leaf = shapes::TextLeaf {
+            text,
+            font_family,
+            font_weight,
+            font_style: shapes::FontStyle::from(font_style),
+            font_size,
+            line_height,
+            letter_spacing,
+            decoration: shapes::TextDecoration::from(decoration),
+            transform: shapes::TextTransform::from(transform),
+            color: skia::Color::new(raw_color),
+        };
+        current_shape()?
+            .add_text_leaf(leaf)
+            .map_err(Error::invalid_state)
+    }))
 }
 
 #[no_mangle]
@@ -605,24 +917,21 @@ pub extern "C" fn add_shape_shadow(
     y: f32,
     raw_style: u8,
     hidden: bool,
-) {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
+) -> u8 {
+    error::status(current_shape().map(|shape| {
         let color = skia::Color::new(raw_color);
         let style = shapes::ShadowStyle::from(raw_style);
         let shadow = shapes::Shadow::new(color, blur, spread, (x, y), style, hidden);
         shape.add_shadow(shadow);
-    }
+    }))
 }
 
 #[no_mangle]
-pub extern "C" fn clear_shape_shadows() {
-    let state = unsafe { STATE.as_mut() }.expect("got an invalid state pointer");
-    if let Some(shape) = state.current_shape() {
-        shape.clear_shadows();
-    }
+pub extern "C" fn clear_shape_shadows() -> u8 {
+    error::status(current_shape().map(|shape| shape.clear_shadows()))
 }
 
 fn main() {
+    #[cfg(target_os = "emscripten")]
     init_gl();
 }
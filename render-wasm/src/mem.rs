@@ -1,32 +1,191 @@
-static mut BUFFERU8: Option<Box<Vec<u8>>> = None;
+use crate::error::{handle_status, pointer_status, status, Error, Result};
 
-#[no_mangle]
-pub extern "C" fn alloc_bytes(len: usize) -> *mut u8 {
-    // TODO: Figure out how to deal with Result<T> from Emscripten
-    if unsafe { BUFFERU8.is_some() } {
-        panic!("Bytes already allocated");
+/// Identifies a buffer of the [Allocator]. The lower 16 bits are its slot (starting at `1`, so
+/// `0` is never a valid handle), and the upper 16 bits the generation of the slot when it was
+/// allocated, so a handle is stale once its buffer has been freed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Handle(u32);
+
+impl Handle {
+    fn new(index: usize, generation: u16) -> Self {
+        Self(((generation as u32) << 16) | (index as u32 + 1))
+    }
+
+    fn index(&self) -> Option<usize> {
+        ((self.0 & 0xffff) as usize).checked_sub(1)
     }
 
-    let mut buffer = Box::new(vec![0u8; len]);
-    let ptr = buffer.as_mut_ptr();
+    fn generation(&self) -> u16 {
+        (self.0 >> 16) as u16
+    }
+}
+
+impl From<u32> for Handle {
+    fn from(value: u32) -> Self {
+        Self(value)
+    }
+}
+
+impl From<Handle> for u32 {
+    fn from(handle: Handle) -> Self {
+        handle.0
+    }
+}
+
+#[derive(Debug, Default)]
+struct Slot {
+    generation: u16,
+    buffer: Option<Vec<u8>>,
+}
 
-    unsafe { BUFFERU8 = Some(buffer) };
-    return ptr;
+/// Buffers shared with JS. Each one is owned by the allocator until it is taken by the function
+/// consuming it, or freed by JS.
+#[derive(Debug, Default)]
+pub struct Allocator {
+    slots: Vec<Slot>,
+    free_slots: Vec<usize>,
 }
 
-pub fn free_bytes() {
-    if unsafe { BUFFERU8.is_some() } {
-        let buffer = unsafe { BUFFERU8.take() }.expect("uninitialized buffer");
-        std::mem::drop(buffer);
+impl Allocator {
+    pub const fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+        }
+    }
+
+    pub fn alloc(&mut self, len: usize) -> Result<Handle> {
+        self.insert(vec![0u8; len])
+    }
+
+    /// Moves the given bytes to a new buffer.
+    pub fn insert(&mut self, bytes: Vec<u8>) -> Result<Handle> {
+        let index = match self.free_slots.pop() {
+            Some(index) => index,
+            None if self.slots.len() < u16::MAX as usize => {
+                self.slots.push(Slot::default());
+                self.slots.len() - 1
+            }
+            None => return Err(Error::memory("Too many buffers allocated")),
+        };
+
+        let slot = &mut self.slots[index];
+        slot.buffer = Some(bytes);
+        Ok(Handle::new(index, slot.generation))
+    }
+
+    pub fn ptr(&mut self, handle: Handle) -> Result<*mut u8> {
+        Ok(self
+            .slot(handle)?
+            .buffer
+            .as_mut()
+            .map_or(std::ptr::null_mut(), Vec::as_mut_ptr))
+    }
+
+    /// Takes the bytes of a buffer, which is freed.
+    pub fn take(&mut self, handle: Handle) -> Result<Vec<u8>> {
+        let bytes = self.slot(handle)?.buffer.take().unwrap_or_default();
+        self.release(handle);
+        Ok(bytes)
+    }
+
+    pub fn free(&mut self, handle: Handle) -> Result<()> {
+        self.take(handle).map(|_| ())
+    }
+
+    pub fn free_all(&mut self) {
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if slot.buffer.take().is_some() {
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free_slots.push(index);
+            }
+        }
+    }
+
+    fn slot(&mut self, handle: Handle) -> Result<&mut Slot> {
+        handle
+            .index()
+            .and_then(|index| self.slots.get_mut(index))
+            .filter(|slot| slot.generation == handle.generation() && slot.buffer.is_some())
+            .ok_or_else(|| Error::memory(format!("Invalid or stale buffer handle {}", handle.0)))
     }
+
+    // The handle has been checked by [Allocator::slot]
+    fn release(&mut self, handle: Handle) {
+        if let Some(index) = handle.index() {
+            let slot = &mut self.slots[index];
+            slot.generation = slot.generation.wrapping_add(1);
+            self.free_slots.push(index);
+        }
+    }
+}
+
+static mut ALLOCATOR: Allocator = Allocator::new();
+
+fn allocator() -> &'static mut Allocator {
+    unsafe { &mut *std::ptr::addr_of_mut!(ALLOCATOR) }
+}
+
+/// Allocates a buffer of `len` bytes and returns its handle, or `0` if it fails (see
+/// [crate::error]). JS writes into it through [get_buffer_ptr] and passes the handle to the
+/// function consuming it.
+#[no_mangle]
+pub extern "C" fn alloc_bytes(len: usize) -> u32 {
+    handle_status(allocator().alloc(len))
+}
+
+/// Returns a pointer to the buffer, or null if the handle is stale.
+#[no_mangle]
+pub extern "C" fn get_buffer_ptr(handle: u32) -> *mut u8 {
+    pointer_status(allocator().ptr(handle.into()))
+}
+
+/// Frees a buffer that was not consumed, like the ones returned to JS by [write_bytes].
+#[no_mangle]
+pub extern "C" fn free_bytes(handle: u32) -> u8 {
+    status(allocator().free(handle.into()))
 }
 
-pub fn buffer_ptr() -> *mut u8 {
-    let buffer = unsafe { BUFFERU8.as_mut() }.expect("uninitializied buffer");
-    buffer.as_mut_ptr()
+/// Moves the given bytes to a new buffer, prefixed by their length as a little endian `u32`,
+/// and returns its handle. JS must call [free_bytes] once it has copied them.
+pub fn write_bytes(bytes: Vec<u8>) -> Result<Handle> {
+    let mut buffer = Vec::with_capacity(bytes.len() + 4);
+    buffer.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buffer.extend(bytes);
+    allocator().insert(buffer)
 }
 
-pub fn bytes() -> Vec<u8> {
-    let buffer = unsafe { BUFFERU8.take() }.expect("uninitialized buffer");
-    *buffer
+/// Takes the bytes of a buffer allocated by JS, which is freed.
+pub fn bytes(handle: u32) -> Result<Vec<u8>> {
+    allocator().take(handle.into())
+}
+
+pub fn free_all() {
+    allocator().free_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffers_are_independent_and_stale_handles_are_rejected() {
+        let mut allocator = Allocator::new();
+        let a = allocator.alloc(4).unwrap();
+        let b = allocator.insert(vec![1, 2, 3]).unwrap();
+        assert_ne!(allocator.ptr(a).unwrap(), allocator.ptr(b).unwrap());
+
+        assert_eq!(allocator.take(b).unwrap(), vec![1, 2, 3]);
+        assert!(allocator.take(b).is_err());
+
+        // The slot is reused, but the old handle still does not match it
+        let c = allocator.alloc(2).unwrap();
+        assert_eq!(c.index(), b.index());
+        assert!(allocator.ptr(b).is_err());
+        assert_eq!(allocator.take(c).unwrap(), vec![0, 0]);
+
+        allocator.free_all();
+        assert!(allocator.ptr(a).is_err());
+        assert!(allocator.ptr(Handle::from(0)).is_err());
+    }
 }
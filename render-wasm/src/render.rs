@@ -1,33 +1,57 @@
 use skia::Contains;
 use skia_safe as skia;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use uuid::Uuid;
 
+use crate::math;
+use crate::spatial::SpatialIndex;
 use crate::view::Viewbox;
 
+mod backend;
 mod blend;
+mod blurs;
 mod cache;
 mod debug;
+mod export;
 mod fills;
 mod gpu_state;
 mod images;
 mod options;
+mod pdf;
 mod shadows;
 mod strokes;
+mod svg;
+mod text;
+mod tiles;
 
 use crate::shapes::{Kind, Shape};
-use cache::CachedSurfaceImage;
+use backend::Backend;
 use gpu_state::GpuState;
 use options::RenderOptions;
+use tiles::{Tile, TileCache, SURFACE_SIZE, TILE_MARGIN, TILE_SIZE};
 
 pub use blend::BlendMode;
+pub use cache::*;
+pub use export::ExportFormat;
 pub use images::*;
 
 const DEFAULT_FONT_BYTES: &[u8] =
     include_bytes!("../../frontend/resources/fonts/RobotoMono-Regular.ttf");
+const DEFAULT_FONT_FAMILY: &str = "robotomono-regular";
+
+fn new_font_collection(
+    font_provider: &skia::textlayout::TypefaceFontProvider,
+) -> skia::textlayout::FontCollection {
+    let mut font_collection = skia::textlayout::FontCollection::new();
+    font_collection.set_default_font_manager(
+        skia::FontMgr::from(font_provider.clone()),
+        DEFAULT_FONT_FAMILY,
+    );
+    font_collection
+}
 
 pub(crate) struct RenderState {
-    gpu_state: GpuState,
+    backend: Backend,
     options: RenderOptions,
 
     // TODO: Probably we're going to need
@@ -37,55 +61,106 @@ pub(crate) struct RenderState {
     pub drawing_surface: skia::Surface,
     pub shadow_surface: skia::Surface,
     pub debug_surface: skia::Surface,
+    // Shapes are rendered here by tiles, which are later composited into the final surface
+    tiles_surface: skia::Surface,
     pub font_provider: skia::textlayout::TypefaceFontProvider,
-    pub cached_surface_image: Option<CachedSurfaceImage>,
+    pub font_collection: skia::textlayout::FontCollection,
+    pub tiles: TileCache,
+    pub index: SpatialIndex,
+    // When set, only these shapes are traversed by `render_shape_tree`
+    visible_shapes: Option<HashSet<Uuid>>,
     pub viewbox: Viewbox,
     pub images: ImageStore,
+    pub cache: RenderCache,
     pub background_color: skia::Color,
+    // When not empty, shapes are recorded as vectors into the last recorder
+    // instead of being drawn on the surfaces (see `record_shape_tree`).
+    vector_recorders: Vec<skia::PictureRecorder>,
+}
+
+fn new_surface(
+    surface: &mut skia::Surface,
+    (width, height): (i32, i32),
+) -> Result<skia::Surface, String> {
+    surface
+        .new_surface_with_dimensions((width, height))
+        .ok_or_else(|| format!("Could not create a {}x{} surface", width, height))
+}
+
+// Recorders are always recording until they are popped. Were one not, the shapes would be
+// drawn on the surfaces instead, and popping it would fail.
+fn recording_canvas(recorders: &mut [skia::PictureRecorder]) -> Option<&skia::Canvas> {
+    recorders.last_mut()?.recording_canvas()
+}
+
+/// Memory spent in the tiles kept out of the viewport, so panning back and forth does not
+/// need to render them again.
+const TILE_CACHE_BUDGET: usize = 128 * 1024 * 1024;
+
+// The visible tiles are always kept, plus up to two more screens within the budget
+fn tile_cache_capacity(columns: i32, rows: i32) -> usize {
+    let visible = (columns * rows) as usize;
+    let tile_bytes = (TILE_SIZE * TILE_SIZE * 4) as usize;
+    visible + (visible * 2).min(TILE_CACHE_BUDGET / tile_bytes)
 }
 
 impl RenderState {
-    pub fn new(width: i32, height: i32) -> RenderState {
+    pub fn new(width: i32, height: i32) -> Result<RenderState, String> {
         // This needs to be done once per WebGL context.
-        let mut gpu_state = GpuState::new();
-        let mut final_surface = gpu_state.create_target_surface(width, height);
-        let shadow_surface = final_surface
-            .new_surface_with_dimensions((width, height))
-            .unwrap();
-        let drawing_surface = final_surface
-            .new_surface_with_dimensions((width, height))
-            .unwrap();
-        let debug_surface = final_surface
-            .new_surface_with_dimensions((width, height))
-            .unwrap();
+        Self::with_backend(Backend::Gpu(GpuState::new()?), width, height)
+    }
+
+    /// Creates a render state drawing into CPU memory, so it does not need a WebGL context.
+    pub fn new_raster(width: i32, height: i32) -> Result<RenderState, String> {
+        Self::with_backend(Backend::Raster, width, height)
+    }
+
+    fn with_backend(mut backend: Backend, width: i32, height: i32) -> Result<RenderState, String> {
+        let mut final_surface = backend.create_target_surface(width, height)?;
+        let (columns, rows) = tiles::grid_size(width, height);
+        let scratch = (SURFACE_SIZE, SURFACE_SIZE);
+        let tiles_surface = new_surface(&mut final_surface, scratch)?;
+        let shadow_surface = new_surface(&mut final_surface, scratch)?;
+        let drawing_surface = new_surface(&mut final_surface, scratch)?;
+        let debug_surface = new_surface(&mut final_surface, (width, height))?;
 
         let mut font_provider = skia::textlayout::TypefaceFontProvider::new();
         let default_font = skia::FontMgr::default()
             .new_from_data(DEFAULT_FONT_BYTES, None)
-            .expect("Failed to load font");
-        font_provider.register_typeface(default_font, "robotomono-regular");
+            .ok_or("Could not load the default font")?;
+        font_provider.register_typeface(default_font, DEFAULT_FONT_FAMILY);
+        let font_collection = new_font_collection(&font_provider);
 
-        RenderState {
-            gpu_state,
+        Ok(RenderState {
+            backend,
             final_surface,
             shadow_surface,
             drawing_surface,
             debug_surface,
-            cached_surface_image: None,
+            tiles_surface,
+            tiles: TileCache::new(tile_cache_capacity(columns, rows)),
+            index: SpatialIndex::new(),
+            visible_shapes: None,
             font_provider,
+            font_collection,
             options: RenderOptions::default(),
             viewbox: Viewbox::new(width as f32, height as f32),
-            images: ImageStore::new(),
+            images: ImageStore::new(DEFAULT_IMAGE_BUDGET),
+            cache: RenderCache::new(),
             background_color: skia::Color::TRANSPARENT,
-        }
+            vector_recorders: vec![],
+        })
     }
 
     pub fn add_font(&mut self, family_name: String, font_data: &[u8]) -> Result<(), String> {
         let typeface = skia::FontMgr::default()
             .new_from_data(font_data, None)
-            .expect("Failed to add font");
+            .ok_or("Error decoding font data")?;
         self.font_provider
             .register_typeface(typeface, family_name.as_ref());
+        // Paragraphs cache the typefaces they resolve, so we need a fresh
+        // collection to pick up the new font.
+        self.font_collection = new_font_collection(&self.font_provider);
         Ok(())
     }
 
@@ -97,51 +172,54 @@ impl RenderState {
         self.images.contains(id)
     }
 
+    pub fn release_image(&mut self, id: &Uuid) {
+        self.images.remove(id);
+    }
+
+    pub fn set_image_budget(&mut self, budget: usize) {
+        self.images.set_budget(budget);
+    }
+
     pub fn set_debug_flags(&mut self, debug: u32) {
         self.options.debug_flags = debug;
     }
 
-    pub fn set_dpr(&mut self, dpr: f32) {
+    pub fn set_dpr(&mut self, dpr: f32) -> Result<(), String> {
         if Some(dpr) != self.options.dpr {
             self.options.dpr = Some(dpr);
             self.resize(
                 self.viewbox.width.floor() as i32,
                 self.viewbox.height.floor() as i32,
-            );
+            )?;
         }
+        Ok(())
     }
 
     pub fn set_background_color(&mut self, color: skia::Color) {
         self.background_color = color;
-        let _ = self.render_all_from_cache();
+        self.render_from_cache();
     }
 
-    pub fn resize(&mut self, width: i32, height: i32) {
+    pub fn resize(&mut self, width: i32, height: i32) -> Result<(), String> {
         let dpr_width = (width as f32 * self.options.dpr()).floor() as i32;
         let dpr_height = (height as f32 * self.options.dpr()).floor() as i32;
 
-        let surface = self.gpu_state.create_target_surface(dpr_width, dpr_height);
-        self.final_surface = surface;
-        self.shadow_surface = self
-            .final_surface
-            .new_surface_with_dimensions((dpr_width, dpr_height))
-            .unwrap();
-        self.drawing_surface = self
-            .final_surface
-            .new_surface_with_dimensions((dpr_width, dpr_height))
-            .unwrap();
-        self.debug_surface = self
-            .final_surface
-            .new_surface_with_dimensions((dpr_width, dpr_height))
-            .unwrap();
+        // The surfaces are only replaced once all of them have been created
+        let mut final_surface = self.backend.create_target_surface(dpr_width, dpr_height)?;
+        let (columns, rows) = tiles::grid_size(dpr_width, dpr_height);
+        let debug_surface = new_surface(&mut final_surface, (dpr_width, dpr_height))?;
+
+        // The scratch surfaces are the size of a tile, which does not depend on the viewport
+        self.final_surface = final_surface;
+        self.debug_surface = debug_surface;
+        self.tiles.set_capacity(tile_cache_capacity(columns, rows));
 
         self.viewbox.set_wh(width as f32, height as f32);
+        Ok(())
     }
 
     pub fn flush(&mut self) {
-        self.gpu_state
-            .context
-            .flush_and_submit_surface(&mut self.final_surface, None);
+        self.backend.flush_and_submit(&mut self.final_surface);
     }
 
     pub fn translate(&mut self, dx: f32, dy: f32) {
@@ -171,10 +249,75 @@ impl RenderState {
             .reset_matrix();
     }
 
+    fn is_vector_mode(&self) -> bool {
+        !self.vector_recorders.is_empty()
+    }
+
+    /// Canvas where the shapes are drawn before being composited into the final canvas.
+    pub fn drawing_canvas(&mut self) -> &skia::Canvas {
+        match recording_canvas(&mut self.vector_recorders) {
+            Some(canvas) => canvas,
+            None => self.drawing_surface.canvas(),
+        }
+    }
+
+    /// Canvas where the shapes are composited. In vector mode it is the same as the drawing canvas.
+    pub fn final_canvas(&mut self) -> &skia::Canvas {
+        match recording_canvas(&mut self.vector_recorders) {
+            Some(canvas) => canvas,
+            None => self.final_surface.canvas(),
+        }
+    }
+
+    fn push_vector_recorder(&mut self, bounds: math::Rect) {
+        let mut recorder = skia::PictureRecorder::new();
+        recorder.begin_recording(bounds, None);
+        self.vector_recorders.push(recorder);
+    }
+
+    fn pop_vector_recorder(&mut self) -> Option<skia::Picture> {
+        self.vector_recorders
+            .pop()?
+            .finish_recording_as_picture(None)
+    }
+
+    /// Records the subtree rooted at the given shape as vectors, in world coordinates.
+    /// Only the shapes inside `area` are recorded.
+    fn record_shape_tree(
+        &mut self,
+        id: &Uuid,
+        tree: &HashMap<Uuid, Shape>,
+        area: math::Rect,
+    ) -> Result<skia::Picture, String> {
+        let viewbox = self.viewbox;
+        let options = self.options;
+        // Vectors are resolution independent, so the filters do not need to be scaled
+        self.viewbox = Viewbox {
+            pan_x: 0.,
+            pan_y: 0.,
+            width: area.width(),
+            height: area.height(),
+            zoom: 1.,
+            area,
+        };
+        self.options.dpr = None;
+
+        self.push_vector_recorder(area);
+        let result = self.render_shape_tree(id, tree);
+        let picture = self.pop_vector_recorder();
+
+        self.viewbox = viewbox;
+        self.options = options;
+        result?;
+        picture.ok_or_else(|| "Could not record the shapes".to_string())
+    }
+
     pub fn apply_drawing_to_final_canvas(&mut self) {
-        self.gpu_state
-            .context
-            .flush_and_submit_surface(&mut self.drawing_surface, None);
+        if self.is_vector_mode() {
+            return;
+        }
+
+        self.backend.flush_and_submit(&mut self.drawing_surface);
 
         self.drawing_surface.draw(
             &mut self.final_surface.canvas(),
@@ -190,103 +333,194 @@ impl RenderState {
             .clear(skia::Color::TRANSPARENT);
     }
 
-    pub fn render_shape(&mut self, shape: &mut Shape, clip: bool) {
-        let transform = shape.transform.to_skia_matrix();
+    pub fn render_shape(&mut self, shape: &Shape) -> Result<(), String> {
+        let picture = self.shape_picture(shape, PictureLayer::Content)?;
+        let is_vector_mode = self.is_vector_mode();
+        if is_vector_mode {
+            shadows::render_vector_drop_shadows(self, shape, &picture);
+        }
+        self.drawing_canvas().draw_picture(&picture, None, None);
+
+        if is_vector_mode {
+            return Ok(());
+        }
+
+        for shadow in shape.drop_shadows().rev().filter(|s| !s.hidden()) {
+            shadows::render_drop_shadow(self, shadow, self.viewbox.zoom * self.options.dpr());
+        }
+
+        self.apply_drawing_to_final_canvas();
+        Ok(())
+    }
+
+    /// Draws the strokes of a frame, which go above its children.
+    fn render_frame_strokes(&mut self, shape: &Shape) -> Result<(), String> {
+        if shape.strokes.is_empty() {
+            return Ok(());
+        }
+        let picture = self.shape_picture(shape, PictureLayer::Strokes)?;
+        self.drawing_canvas().draw_picture(&picture, None, None);
+        self.apply_drawing_to_final_canvas();
+        Ok(())
+    }
 
-        // Check transform-matrix code from common/src/app/common/geom/shapes/transforms.cljc
-        let center = shape.bounds().center();
-        let mut matrix = skia::Matrix::new_identity();
-        matrix.pre_translate(center);
-        matrix.pre_concat(&transform);
-        matrix.pre_translate(-center);
+    /// Returns the given layer of the shape recorded in world coordinates. It is replayed until
+    /// the shape changes, and kept for a few scales, as strokes are adjusted to them.
+    fn shape_picture(
+        &mut self,
+        shape: &Shape,
+        layer: PictureLayer,
+    ) -> Result<skia::Picture, String> {
+        let scale = self.viewbox.zoom * self.options.dpr();
+        if let Some(picture) = self.cache.get(&shape.id).picture(layer, scale) {
+            return Ok(picture);
+        }
 
-        self.drawing_surface.canvas().concat(&matrix);
+        self.push_vector_recorder(shape.visual_bounds());
+        self.drawing_canvas().concat(&shape.centered_transform());
+        let result = match layer {
+            PictureLayer::Content => self.render_shape_content(shape),
+            PictureLayer::Strokes => {
+                self.render_shape_strokes(shape);
+                Ok(())
+            }
+        };
+        // The recorder is popped even when the shape could not be rendered
+        let picture = self
+            .pop_vector_recorder()
+            .ok_or("Could not record the shape")?;
+        result?;
+        // Pictures keep their images alive, which would defeat the image budget
+        if shape.image_ids().next().is_none() {
+            self.cache
+                .get(&shape.id)
+                .set_picture(layer, scale, picture.clone());
+        }
+        Ok(picture)
+    }
 
+    fn render_shape_content(&mut self, shape: &Shape) -> Result<(), String> {
         match &shape.kind {
             Kind::SVGRaw(sr) => {
-                if let Some(svg) = shape.svg.as_ref() {
-                    svg.render(self.drawing_surface.canvas())
-                } else {
-                    let font_manager = skia::FontMgr::from(self.font_provider.clone());
-                    let dom_result = skia::svg::Dom::from_str(sr.content.to_string(), font_manager);
-                    match dom_result {
-                        Ok(dom) => {
-                            dom.render(self.drawing_surface.canvas());
-                            shape.set_svg(dom);
-                        }
-                        Err(e) => {
-                            eprintln!("Error parsing SVG. Error: {}", e);
-                        }
-                    }
-                }
+                let font_manager = skia::FontMgr::from(self.font_provider.clone());
+                let dom = self.cache.get(&shape.id).svg(&sr.content, font_manager)?;
+                dom.render(self.drawing_canvas());
+            }
+            Kind::Text(content) => {
+                text::render(self, shape, content);
             }
             _ => {
-                for fill in shape.fills().rev() {
-                    fills::render(self, shape, fill);
+                // Inner shadows are drawn out of the fills, so they are recorded apart
+                let has_inner_shadows = shape.inner_shadows().any(|s| !s.hidden());
+                if has_inner_shadows {
+                    self.push_vector_recorder(shape.visual_bounds());
+                    self.drawing_canvas().concat(&shape.centered_transform());
+                }
+
+                for (index, fill) in shape.fills().enumerate().rev() {
+                    fills::render(self, shape, index, fill);
+                }
+
+                if has_inner_shadows {
+                    let fills_picture = self
+                        .pop_vector_recorder()
+                        .ok_or("Could not record the fills")?;
+                    shadows::render_vector_inner_shadows(self, shape, &fills_picture);
                 }
 
-                for stroke in shape.strokes().rev() {
-                    strokes::render(self, shape, stroke);
+                if !matches!(shape.kind, Kind::Frame(_, _)) {
+                    self.render_shape_strokes(shape);
                 }
             }
         };
+        Ok(())
+    }
 
-        if clip {
-            self.drawing_surface
-                .canvas()
-                .clip_rect(shape.bounds(), skia::ClipOp::Intersect, true);
+    fn render_shape_strokes(&mut self, shape: &Shape) {
+        for stroke in shape.strokes().rev() {
+            strokes::render(self, shape, stroke);
         }
+    }
 
-        for shadow in shape.drop_shadows().rev().filter(|s| !s.hidden()) {
-            shadows::render_drop_shadow(self, shadow, self.viewbox.zoom * self.options.dpr());
+    /// Clips the drawing and final canvases to the outline of the shape, in world coordinates.
+    fn clip_to_outline(&mut self, shape: &Shape) {
+        let outline = match shape.to_world_path() {
+            Some(outline) => outline,
+            None => return,
+        };
+        let matrix = self.drawing_canvas().local_to_device_as_3x3();
+        self.drawing_canvas()
+            .clip_path(&outline, skia::ClipOp::Intersect, true);
+        // In vector mode both canvases are the same
+        if !self.is_vector_mode() {
+            // The final canvas is not transformed, so the outline is mapped to device coordinates
+            self.final_canvas().clip_path(
+                &outline.with_transform(&matrix),
+                skia::ClipOp::Intersect,
+                true,
+            );
         }
-
-        self.apply_drawing_to_final_canvas();
     }
 
     pub fn zoom(&mut self, tree: &HashMap<Uuid, Shape>) -> Result<(), String> {
-        if let Some(cached_surface_image) = self.cached_surface_image.as_mut() {
-            let is_dirty = cached_surface_image.is_dirty_for_zooming(&self.viewbox);
-            if is_dirty {
-                self.render_all(tree, true);
-            } else {
-                self.render_all_from_cache()?;
-            }
-        }
-
-        Ok(())
+        // Only the tiles of the new scale that are not cached yet are rendered
+        self.render_all(tree, false)
     }
 
     pub fn pan(&mut self, tree: &HashMap<Uuid, Shape>) -> Result<(), String> {
-        if let Some(cached_surface_image) = self.cached_surface_image.as_mut() {
-            let is_dirty = cached_surface_image.is_dirty_for_panning(&self.viewbox);
-            if is_dirty {
-                self.render_all(tree, true);
-            } else {
-                self.render_all_from_cache()?;
-            }
+        self.render_all(tree, false)
+    }
+
+    /// Renders the tiles of the viewbox that are not cached yet, and composites them all into
+    /// the final surface. When `invalidate_tiles` is set, every tile is rendered again and the
+    /// spatial index is built from scratch.
+    pub fn render_all(
+        &mut self,
+        tree: &HashMap<Uuid, Shape>,
+        invalidate_tiles: bool,
+    ) -> Result<(), String> {
+        if invalidate_tiles {
+            self.tiles.clear();
+            self.index.rebuild(tree);
         }
+        self.render_frame(tree, HashMap::new(), None)
+    }
 
-        Ok(())
+    /// Like [RenderState::render_all], but only the given area (in world coordinates) is
+    /// painted again on top of the cached tiles. The changed shapes must have been updated
+    /// in the spatial index.
+    pub fn render_damage(
+        &mut self,
+        tree: &HashMap<Uuid, Shape>,
+        damage: math::Rect,
+    ) -> Result<(), String> {
+        // The damaged tiles out of the viewbox, or at other scales, are just discarded
+        let damaged_tiles = self.tiles.take_intersecting(&damage);
+        self.render_frame(tree, damaged_tiles, Some(damage))
     }
 
-    pub fn render_all(&mut self, tree: &HashMap<Uuid, Shape>, generate_cached_surface_image: bool) {
-        self.reset_canvas();
-        self.scale(
-            self.viewbox.zoom * self.options.dpr(),
-            self.viewbox.zoom * self.options.dpr(),
-        );
-        self.translate(self.viewbox.pan_x, self.viewbox.pan_y);
+    /// Renders the missing tiles and composites the frame. The shapes that fail to render are
+    /// left out, and the first of their errors is returned once the frame is complete.
+    fn render_frame(
+        &mut self,
+        tree: &HashMap<Uuid, Shape>,
+        mut damaged_tiles: HashMap<Tile, Image>,
+        damage: Option<math::Rect>,
+    ) -> Result<(), String> {
+        self.debug_surface
+            .canvas()
+            .clear(skia::Color::TRANSPARENT)
+            .reset_matrix();
 
-        // Reset shape tree
-        let is_complete = self.render_shape_tree(&Uuid::nil(), tree);
-        if generate_cached_surface_image || self.cached_surface_image.is_none() {
-            self.cached_surface_image = Some(CachedSurfaceImage {
-                image: self.final_surface.image_snapshot(),
-                viewbox: self.viewbox,
-                has_all_shapes: is_complete,
-            });
-        }
+        let scale = self.viewbox.zoom * self.options.dpr();
+        let missing_tiles: Vec<_> = self
+            .missing_tiles(scale)
+            .into_iter()
+            .map(|tile| (tile, damaged_tiles.remove(&tile)))
+            .collect();
+        let result = self.render_tiles(tree, &missing_tiles, damage);
+
+        self.composite_tiles(scale);
 
         if self.options.is_debug_visible() {
             self.render_debug();
@@ -295,100 +529,865 @@ impl RenderState {
         debug::render_wasm_label(self);
 
         self.flush();
+        result
     }
 
-    fn render_all_from_cache(&mut self) -> Result<(), String> {
-        self.reset_canvas();
+    /// Returns the visible tiles at the given scale that are not cached.
+    fn missing_tiles(&mut self, scale: f32) -> Vec<Tile> {
+        let (left, top, right, bottom) = tiles::tile_range(&self.viewbox.area, scale);
+        let mut missing = vec![];
+        for y in top..=bottom {
+            for x in left..=right {
+                let tile = Tile::new(scale, x, y);
+                // Getting the tile marks it as recently used
+                if self.tiles.get(&tile).is_none() {
+                    missing.push(tile);
+                }
+            }
+        }
+        missing
+    }
 
-        let cached = self
-            .cached_surface_image
-            .as_ref()
-            .ok_or("Uninitialized cached surface image")?;
+    /// Renders the given tiles one at a time and caches them. The tiles that come with
+    /// their previous image are only painted again inside `damage`.
+    fn render_tiles(
+        &mut self,
+        tree: &HashMap<Uuid, Shape>,
+        tiles: &[(Tile, Option<Image>)],
+        damage: Option<math::Rect>,
+    ) -> Result<(), String> {
+        let mut result = Ok(());
+        std::mem::swap(&mut self.final_surface, &mut self.tiles_surface);
+        for (tile, previous) in tiles {
+            result = result.and(self.render_tile(tree, tile, previous.as_ref(), damage));
+        }
+        std::mem::swap(&mut self.final_surface, &mut self.tiles_surface);
+        result
+    }
 
-        let image = &cached.image;
-        let paint = skia::Paint::default();
-        self.final_surface.canvas().save();
-        self.drawing_surface.canvas().save();
+    /// Renders a tile in the middle of the tiles surface, so the margin around it fits too.
+    fn render_tile(
+        &mut self,
+        tree: &HashMap<Uuid, Shape>,
+        tile: &Tile,
+        previous: Option<&Image>,
+        damage: Option<math::Rect>,
+    ) -> Result<(), String> {
+        let scale = tile.scale();
+        let world_tile_size = tiles::world_tile_size(scale);
+        let origin = (tile.x - TILE_MARGIN, tile.y - TILE_MARGIN);
+        let tile_bounds = tiles::device_rect(tile, origin);
+
+        let mut bounds = tile_bounds;
+        let mut area = tile.world_rect();
+        if let (Some(image), Some(damage)) = (previous, damage) {
+            let damaged_area = tiles::device_area(&damage, scale, origin);
+            match skia::IRect::intersect(&bounds, &damaged_area) {
+                Some(damaged_bounds) => bounds = damaged_bounds,
+                None => {
+                    self.tiles.insert(*tile, image.clone());
+                    return Ok(());
+                }
+            }
+            area.intersect(damage);
+        }
 
-        let navigate_zoom = self.viewbox.zoom / cached.viewbox.zoom;
-        let navigate_x = cached.viewbox.zoom * (self.viewbox.pan_x - cached.viewbox.pan_x);
-        let navigate_y = cached.viewbox.zoom * (self.viewbox.pan_y - cached.viewbox.pan_y);
+        for surface in [
+            &mut self.final_surface,
+            &mut self.drawing_surface,
+            &mut self.shadow_surface,
+        ] {
+            surface
+                .canvas()
+                .clear(skia::Color::TRANSPARENT)
+                .reset_matrix();
+        }
+        if let Some(image) = previous {
+            let left_top = (tile_bounds.left as f32, tile_bounds.top as f32);
+            self.final_surface
+                .canvas()
+                .draw_image(image, left_top, None);
+        }
 
-        self.final_surface
-            .canvas()
-            .scale((navigate_zoom, navigate_zoom));
-        self.final_surface.canvas().translate((
-            navigate_x * self.options.dpr(),
-            navigate_y * self.options.dpr(),
-        ));
-        self.final_surface
-            .canvas()
-            .draw_image(image.clone(), (0, 0), Some(&paint));
+        // Background blurs read what lies beneath them, which may be out of the tile
+        let margin = if self.has_background_blurs(tree, &area, scale) {
+            TILE_MARGIN as f32
+        } else {
+            0.
+        };
+        let device_margin = margin * TILE_SIZE as f32;
+        let clip = math::Rect::from(bounds).with_outset((device_margin, device_margin));
+        let world_margin = margin * world_tile_size;
+        let area = area.with_outset((world_margin, world_margin));
+
+        for surface in [&mut self.final_surface, &mut self.drawing_surface] {
+            surface.canvas().save();
+            surface
+                .canvas()
+                .clip_rect(clip, skia::ClipOp::Intersect, false);
+        }
+        // Damaged areas are painted from scratch
+        self.final_surface.canvas().clear(skia::Color::TRANSPARENT);
+        self.scale(scale, scale);
+        self.translate(
+            -origin.0 as f32 * world_tile_size,
+            -origin.1 as f32 * world_tile_size,
+        );
+
+        // Only the shapes in the tile need to be rendered
+        let viewbox = self.viewbox;
+        self.viewbox.area = area;
+        self.visible_shapes = Some(self.index.visible_shapes(&area));
+        let result = self.render_shape_tree(&Uuid::nil(), tree);
+        self.visible_shapes = None;
+        self.viewbox = viewbox;
+
+        if let Some(image) = self.final_surface.image_snapshot_with_bounds(tile_bounds) {
+            self.tiles.insert(*tile, image);
+        }
 
         self.final_surface.canvas().restore();
         self.drawing_surface.canvas().restore();
+        result.map(|_| ())
+    }
+
+    fn has_background_blurs(
+        &self,
+        tree: &HashMap<Uuid, Shape>,
+        area: &math::Rect,
+        scale: f32,
+    ) -> bool {
+        self.index
+            .query(area)
+            .iter()
+            .filter_map(|id| tree.get(id))
+            .any(|shape| !shape.hidden() && shape.backdrop_filter(scale).is_some())
+    }
+
+    /// Draws the cached tiles of the given scale into the final surface, scaling them to
+    /// the current zoom if needed.
+    fn composite_tiles(&mut self, scale: f32) {
+        let current_scale = self.viewbox.zoom * self.options.dpr();
+        let viewbox = self.viewbox;
+        let paint = skia::Paint::default();
+
+        let canvas = self.final_surface.canvas();
+        canvas.clear(self.background_color).reset_matrix();
+        for (tile, image) in self.tiles.tiles_at(scale) {
+            let rect = tile.world_rect();
+            if !rect.intersects(viewbox.area) {
+                continue;
+            }
 
+            let dest = math::Rect::from_ltrb(
+                (rect.left + viewbox.pan_x) * current_scale,
+                (rect.top + viewbox.pan_y) * current_scale,
+                (rect.right + viewbox.pan_x) * current_scale,
+                (rect.bottom + viewbox.pan_y) * current_scale,
+            );
+            canvas.draw_image_rect(image, None, dest, &paint);
+        }
+    }
+
+    fn render_from_cache(&mut self) {
+        let scale = self.viewbox.zoom * self.options.dpr();
+        let scale = self.tiles.last_scale().unwrap_or(scale);
+        self.composite_tiles(scale);
         self.flush();
+    }
 
-        Ok(())
+    pub fn export_shape(
+        &mut self,
+        id: &Uuid,
+        tree: &HashMap<Uuid, Shape>,
+        scale: f32,
+        format: ExportFormat,
+        quality: u32,
+    ) -> Result<Vec<u8>, String> {
+        export::export_shape(self, id, tree, scale, format, quality)
+    }
+
+    pub fn export_pdf(
+        &mut self,
+        ids: &[Uuid],
+        tree: &HashMap<Uuid, Shape>,
+    ) -> Result<Vec<u8>, String> {
+        pdf::export_pdf(self, ids, tree)
+    }
+
+    pub fn export_svg(
+        &mut self,
+        id: &Uuid,
+        tree: &HashMap<Uuid, Shape>,
+        text_to_paths: bool,
+    ) -> Result<String, String> {
+        svg::export_svg(self, id, tree, text_to_paths)
     }
 
     fn render_debug(&mut self) {
         debug::render(self);
     }
 
-    // Returns a boolean indicating if the viewbox contains the rendered shapes
-    fn render_shape_tree(&mut self, root_id: &Uuid, tree: &HashMap<Uuid, Shape>) -> bool {
-        if let Some(element) = tree.get(&root_id) {
-            let mut is_complete = self.viewbox.area.contains(element.bounds());
+    // Returns a boolean indicating if the viewbox contains the rendered shapes. The shapes that
+    // fail to render are left out, and the first of their errors is returned.
+    fn render_shape_tree(
+        &mut self,
+        root_id: &Uuid,
+        tree: &HashMap<Uuid, Shape>,
+    ) -> Result<bool, String> {
+        let element = tree
+            .get(root_id)
+            .ok_or_else(|| format!("Shape {root_id} not found"))?;
+        let mut is_complete = self.viewbox.area.contains(element.visual_bounds());
 
-            if !root_id.is_nil() {
-                if !element.bounds().intersects(self.viewbox.area) || element.hidden() {
-                    debug::render_debug_element(self, element, false);
-                    // TODO: This means that not all the shapes are rendered so we
-                    // need to call a render_all on the zoom out.
-                    return is_complete; // TODO return is_complete or return false??
-                } else {
-                    debug::render_debug_element(self, element, true);
-                }
+        if !root_id.is_nil() {
+            let is_visible = match &self.visible_shapes {
+                Some(visible_shapes) => visible_shapes.contains(root_id),
+                None => element.visual_bounds().intersects(self.viewbox.area),
+            };
+            if !is_visible || element.hidden() {
+                debug::render_debug_element(self, element, false);
+                // TODO: This means that not all the shapes are rendered so we
+                // need to call a render_all on the zoom out.
+                return Ok(is_complete); // TODO return is_complete or return false??
+            } else {
+                debug::render_debug_element(self, element, true);
             }
+        }
 
-            let mut paint = skia::Paint::default();
-            paint.set_blend_mode(element.blend_mode().into());
-            paint.set_alpha_f(element.opacity());
-            let filter = element.image_filter(self.viewbox.zoom * self.options.dpr());
-            if let Some(image_filter) = filter {
-                paint.set_image_filter(image_filter);
+        if !root_id.is_nil() {
+            let backdrop_filter = element.backdrop_filter(self.viewbox.zoom * self.options.dpr());
+            if let Some(backdrop_filter) = backdrop_filter {
+                blurs::render_background_blur(self, element, &backdrop_filter);
             }
+        }
+
+        let mut paint = skia::Paint::default();
+        paint.set_blend_mode(element.blend_mode().into());
+        paint.set_alpha_f(element.opacity());
+        let filter = element.image_filter(self.viewbox.zoom * self.options.dpr());
+        if let Some(image_filter) = filter {
+            paint.set_image_filter(image_filter);
+        }
 
+        // Layers start empty, so they are only opened when the shape has to be composited
+        // as a whole. Otherwise the background blurs of its children could not see what
+        // lies beneath it.
+        let is_isolated = element.opacity() < 1.
+            || element.blend_mode() != BlendMode::default()
+            || paint.image_filter().is_some()
+            || element.mask_id().is_some();
+        if is_isolated {
             let layer_rec = skia::canvas::SaveLayerRec::default().paint(&paint);
-            // This is needed so the next non-children shape does not carry this shape's transform
-            self.final_surface.canvas().save_layer(&layer_rec);
+            self.final_canvas().save_layer(&layer_rec);
+        } else {
+            self.final_canvas().save();
+        }
 
-            self.drawing_surface.canvas().save();
-            if !root_id.is_nil() {
-                self.render_shape(&mut element.clone(), element.clip());
-            } else {
-                self.apply_drawing_to_final_canvas();
+        // The canvases are restored even when a shape fails, so the rest of the tree is rendered
+        let mut result = Ok(());
+        self.drawing_canvas().save();
+        if !root_id.is_nil() {
+            result = self.render_shape(element);
+        } else {
+            self.apply_drawing_to_final_canvas();
+        }
+
+        self.drawing_canvas().restore();
+
+        // draw all the children shapes
+        if element.is_recursive() {
+            let clips_children = element.clips_children();
+            if clips_children {
+                self.drawing_canvas().save();
+                self.final_canvas().save();
+                self.clip_to_outline(element);
             }
 
-            self.drawing_surface.canvas().restore();
+            let mask_id = element.mask_id();
+            for id in element.children_ids() {
+                if Some(id) == mask_id {
+                    continue;
+                }
+                self.drawing_canvas().save();
+                match self.render_shape_tree(&id, tree) {
+                    Ok(is_child_complete) => is_complete = is_child_complete && is_complete,
+                    Err(error) => result = result.and(Err(error)),
+                }
+                self.drawing_canvas().restore();
+            }
 
-            // draw all the children shapes
-            if element.is_recursive() {
-                for id in element.children_ids() {
-                    self.drawing_surface.canvas().save();
-                    is_complete = self.render_shape_tree(&id, tree) && is_complete;
-                    self.drawing_surface.canvas().restore();
+            // The rest of the children are only kept where the mask is painted
+            if let Some(mask_id) = mask_id {
+                let mut mask_paint = skia::Paint::default();
+                mask_paint.set_blend_mode(skia::BlendMode::DstIn);
+                let mask_rec = skia::canvas::SaveLayerRec::default().paint(&mask_paint);
+                self.final_canvas().save_layer(&mask_rec);
+                self.drawing_canvas().save();
+                match self.render_shape_tree(&mask_id, tree) {
+                    Ok(is_mask_complete) => is_complete = is_mask_complete && is_complete,
+                    Err(error) => result = result.and(Err(error)),
                 }
+                self.drawing_canvas().restore();
+                self.final_canvas().restore();
             }
 
-            self.final_surface.canvas().restore();
+            if clips_children {
+                self.final_canvas().restore();
+                self.drawing_canvas().restore();
+            }
+        }
 
-            return is_complete;
-        } else {
-            eprintln!("Error: Element with root_id {root_id} not found in the tree.");
-            return false;
+        // Frames draw their strokes above their children
+        if let Kind::Frame(_, _) = element.kind {
+            result = result.and(self.render_frame_strokes(element));
+        }
+
+        self.final_canvas().restore();
+
+        result.map(|_| is_complete)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::{
+        compute_bool_path, BoolType, Dash, Fill, FontStyle, Group, Paragraph, Path, Shadow,
+        ShadowStyle, Stroke, TextAlign, TextContent, TextDecoration, TextLeaf, TextTransform,
+    };
+
+    fn tree_with_rect(left: f32, top: f32, right: f32, bottom: f32) -> HashMap<Uuid, Shape> {
+        let id = Uuid::new_v4();
+        let mut rect = Shape::new(id);
+        rect.set_selrect(left, top, right, bottom);
+        rect.add_fill(Fill::Solid(skia::Color::RED));
+
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(id);
+
+        HashMap::from([(Uuid::nil(), root), (id, rect)])
+    }
+
+    fn pixel_at(render_state: &mut RenderState, x: i32, y: i32) -> skia::Color {
+        render_state
+            .final_surface
+            .peek_pixels()
+            .expect("raster surfaces can be read")
+            .get_color((x, y))
+    }
+
+    #[test]
+    fn raster_backend_renders_shapes_without_gl() {
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state
+            .render_all(&tree_with_rect(10., 10., 50., 50.), true)
+            .unwrap();
+
+        assert_eq!(pixel_at(&mut render_state, 30, 30), skia::Color::RED);
+        assert_eq!(
+            pixel_at(&mut render_state, 70, 30),
+            skia::Color::TRANSPARENT
+        );
+    }
+
+    #[test]
+    fn raster_backend_keeps_rendering_after_resizing() {
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.resize(200, 150).unwrap();
+        render_state
+            .render_all(&tree_with_rect(120., 10., 180., 50.), true)
+            .unwrap();
+
+        let size = render_state.final_surface.image_info().dimensions();
+        assert_eq!((size.width, size.height), (200, 150));
+        assert_eq!(pixel_at(&mut render_state, 150, 30), skia::Color::RED);
+    }
+
+    #[test]
+    fn missing_shapes_are_reported_after_rendering_the_rest() {
+        let mut tree = tree_with_rect(10., 10., 50., 50.);
+        let missing_id = Uuid::new_v4();
+        tree.get_mut(&Uuid::nil()).unwrap().add_child(missing_id);
+
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        let error = render_state.render_all(&tree, true).unwrap_err();
+
+        assert!(error.contains(&missing_id.to_string()));
+        assert_eq!(pixel_at(&mut render_state, 30, 30), skia::Color::RED);
+    }
+
+    #[test]
+    fn panning_renders_the_tiles_that_are_not_cached() {
+        let tree = tree_with_rect(610., 10., 650., 50.);
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.render_all(&tree, true).unwrap();
+        assert_eq!(
+            pixel_at(&mut render_state, 30, 30),
+            skia::Color::TRANSPARENT
+        );
+
+        render_state.viewbox.set_pan_xy(-600., 0.);
+        render_state.pan(&tree).unwrap();
+
+        assert_eq!(pixel_at(&mut render_state, 30, 30), skia::Color::RED);
+        assert!(render_state.tiles.contains(&Tile::new(1., 0, 0)));
+        assert!(render_state.tiles.contains(&Tile::new(1., 1, 0)));
+    }
+
+    // A clipped frame with a red child covering the whole surface
+    fn tree_with_frame(frame: Shape) -> HashMap<Uuid, Shape> {
+        let mut frame = frame;
+        let child_id = Uuid::new_v4();
+        let mut child = Shape::new(child_id);
+        child.set_selrect(0., 0., 100., 100.);
+        child.add_fill(Fill::Solid(skia::Color::RED));
+        frame.add_child(child_id);
+        frame.set_clip(true);
+
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(frame.id);
+
+        HashMap::from([(Uuid::nil(), root), (frame.id, frame), (child_id, child)])
+    }
+
+    fn frame(left: f32, top: f32, right: f32, bottom: f32) -> Shape {
+        let mut frame = Shape::new(Uuid::new_v4());
+        frame.set_kind(Kind::Frame(math::Rect::new_empty(), None));
+        frame.set_selrect(left, top, right, bottom);
+        frame
+    }
+
+    #[test]
+    fn frames_clip_their_children_to_their_rounded_outline() {
+        let mut rounded = frame(10., 10., 90., 90.);
+        rounded.set_corners((20., 20., 20., 20.));
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state
+            .render_all(&tree_with_frame(rounded), true)
+            .unwrap();
+
+        assert_eq!(pixel_at(&mut render_state, 50, 50), skia::Color::RED);
+        assert_eq!(pixel_at(&mut render_state, 50, 12), skia::Color::RED);
+        // Out of the rounded corner, and out of the frame
+        assert_eq!(
+            pixel_at(&mut render_state, 12, 12),
+            skia::Color::TRANSPARENT
+        );
+        assert_eq!(pixel_at(&mut render_state, 5, 50), skia::Color::TRANSPARENT);
+    }
+
+    #[test]
+    fn frames_clip_their_children_to_their_transformed_outline() {
+        let mut rotated = frame(20., 20., 80., 80.);
+        let (sin, cos) = std::f32::consts::FRAC_PI_4.sin_cos();
+        rotated.set_transform(cos, sin, -sin, cos, 0., 0.);
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state
+            .render_all(&tree_with_frame(rotated), true)
+            .unwrap();
+
+        // The frame is a diamond centered at (50, 50)
+        assert_eq!(pixel_at(&mut render_state, 50, 50), skia::Color::RED);
+        assert_eq!(pixel_at(&mut render_state, 50, 12), skia::Color::RED);
+        assert_eq!(
+            pixel_at(&mut render_state, 24, 24),
+            skia::Color::TRANSPARENT
+        );
+    }
+
+    #[test]
+    fn frames_draw_their_strokes_above_their_children() {
+        let mut stroked = frame(10., 10., 90., 90.);
+        stroked.add_stroke(Stroke::new_inner_stroke(10., 0, 0, 0));
+        stroked
+            .set_stroke_fill(Fill::Solid(skia::Color::BLUE))
+            .unwrap();
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state
+            .render_all(&tree_with_frame(stroked), true)
+            .unwrap();
+
+        assert_eq!(pixel_at(&mut render_state, 15, 50), skia::Color::BLUE);
+        assert_eq!(pixel_at(&mut render_state, 50, 50), skia::Color::RED);
+    }
+
+    #[test]
+    fn dashed_strokes_paint_the_corners_of_rects() {
+        // Fitted around the whole contour, the gap of one of the dashes would fall on the top
+        // right corner of the rect
+        let mut tree = tree_with_rect(10., 10., 80., 50.);
+        let rect = tree.values_mut().find(|shape| !shape.id.is_nil()).unwrap();
+        rect.add_stroke(Stroke::new_center_stroke(4., 0, 0, 0));
+        rect.set_stroke_fill(Fill::Solid(skia::Color::BLUE))
+            .unwrap();
+        rect.set_stroke_dash(Dash::new(vec![10., 30.], 0.)).unwrap();
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.render_all(&tree, true).unwrap();
+
+        for (x, y) in [(9, 9), (80, 9), (80, 50), (9, 50)] {
+            assert_eq!(pixel_at(&mut render_state, x, y), skia::Color::BLUE);
+        }
+        assert_eq!(pixel_at(&mut render_state, 25, 9), skia::Color::TRANSPARENT);
+    }
+
+    // A white shape from (20, 20) to (80, 80) with a black inner shadow towards its bottom
+    // right, so it darkens its top and left edges
+    fn tree_with_inner_shadow(kind: Kind) -> HashMap<Uuid, Shape> {
+        let id = Uuid::new_v4();
+        let mut shape = Shape::new(id);
+        shape.set_kind(kind);
+        shape.set_selrect(20., 20., 80., 80.);
+        shape.add_fill(Fill::Solid(skia::Color::WHITE));
+        shape.add_shadow(Shadow::new(
+            skia::Color::BLACK,
+            0.,
+            0.,
+            (10., 10.),
+            ShadowStyle::Inner,
+            false,
+        ));
+
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(id);
+        let mut tree = HashMap::from([(Uuid::nil(), root)]);
+
+        // Bools are the union of two halves of the square, computed like the state does
+        if let Kind::Bool(bool_type, _) = shape.kind {
+            for (left, right) in [(20., 50.), (50., 80.)] {
+                let mut half = Shape::new(Uuid::new_v4());
+                half.set_selrect(left, 20., right, 80.);
+                shape.add_child(half.id);
+                tree.insert(half.id, half);
+            }
+            let path = compute_bool_path(&shape, &tree).unwrap();
+            shape.set_kind(Kind::Bool(bool_type, Path::from(path)));
+        }
+        tree.insert(id, shape);
+        tree
+    }
+
+    #[test]
+    fn inner_shadows_only_paint_inside_the_shape() {
+        let mut square = skia::Path::new();
+        square.add_rect(math::Rect::from_ltrb(20., 20., 80., 80.), None);
+        let kinds = [
+            Kind::Rect(math::Rect::new_empty(), None),
+            Kind::Rect(math::Rect::new_empty(), Some([(10., 10.).into(); 4])),
+            Kind::Circle(math::Rect::new_empty()),
+            Kind::Path(Path::from(square)),
+            Kind::Bool(BoolType::Union, Path::default()),
+        ];
+
+        for kind in kinds {
+            let description = format!("{:?}", kind);
+            let mut render_state = RenderState::new_raster(100, 100).unwrap();
+            render_state
+                .render_all(&tree_with_inner_shadow(kind), true)
+                .unwrap();
+
+            let shadowed = pixel_at(&mut render_state, 25, 50);
+            assert!(shadowed.a() == 255 && shadowed.r() < 64, "{}", description);
+            let lit = pixel_at(&mut render_state, 70, 50);
+            assert_eq!(lit, skia::Color::WHITE, "{}", description);
+            for (x, y) in [(15, 50), (50, 15), (85, 50), (50, 85), (85, 85)] {
+                let outside = pixel_at(&mut render_state, x, y);
+                assert_eq!(outside, skia::Color::TRANSPARENT, "{} {x} {y}", description);
+            }
+        }
+    }
+
+    #[test]
+    fn inner_shadows_are_drawn_under_the_strokes() {
+        let mut tree = tree_with_inner_shadow(Kind::Rect(math::Rect::new_empty(), None));
+        let id = tree[&Uuid::nil()].children_ids()[0];
+        let shape = tree.get_mut(&id).unwrap();
+        shape.add_stroke(Stroke::new_inner_stroke(6., 0, 0, 0));
+        shape
+            .set_stroke_fill(Fill::Solid(skia::Color::BLUE))
+            .unwrap();
+
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.render_all(&tree, true).unwrap();
+
+        assert_eq!(pixel_at(&mut render_state, 22, 50), skia::Color::BLUE);
+        let shadowed = pixel_at(&mut render_state, 27, 50);
+        assert!(shadowed.a() == 255 && shadowed.r() < 64 && shadowed.b() < 64);
+    }
+
+    // A text from (10, 10) to (90, 90) with a paragraph for each line
+    fn tree_with_text(
+        lines: &[&str],
+        text_align: TextAlign,
+        font_family: &str,
+        line_height: f32,
+    ) -> HashMap<Uuid, Shape> {
+        let id = Uuid::new_v4();
+        let mut text = Shape::new(id);
+        text.set_kind(Kind::Text(TextContent::default()));
+        text.set_selrect(10., 10., 90., 90.);
+        text.add_text_paragraph_set().unwrap();
+        for line in lines {
+            text.add_text_paragraph(Paragraph::new(text_align)).unwrap();
+            text.add_text_leaf(TextLeaf {
+                text: line.to_string(),
+                font_family: font_family.to_string(),
+                font_weight: 400,
+                font_style: FontStyle::Normal,
+                font_size: 16.,
+                line_height,
+                letter_spacing: 0.,
+                decoration: TextDecoration::None,
+                transform: TextTransform::None,
+                color: skia::Color::BLACK,
+            })
+            .unwrap();
+        }
+
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(id);
+        HashMap::from([(Uuid::nil(), root), (id, text)])
+    }
+
+    // Returns the bounds of the painted pixels
+    fn ink_bounds(render_state: &mut RenderState) -> Option<skia::IRect> {
+        let mut bounds: Option<skia::IRect> = None;
+        for y in 0..100 {
+            for x in 0..100 {
+                if pixel_at(render_state, x, y).a() == 0 {
+                    continue;
+                }
+                let pixel = skia::IRect::from_xywh(x, y, 1, 1);
+                bounds = Some(match bounds {
+                    Some(bounds) => skia::IRect::join(&bounds, &pixel),
+                    None => pixel,
+                });
+            }
+        }
+        bounds
+    }
+
+    fn render_text(tree: &HashMap<Uuid, Shape>) -> Option<skia::IRect> {
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state
+            .add_font("testfont".to_string(), DEFAULT_FONT_BYTES)
+            .unwrap();
+        render_state.render_all(tree, true).unwrap();
+        ink_bounds(&mut render_state)
+    }
+
+    #[test]
+    fn texts_are_laid_out_inside_their_selrect_with_their_alignment() {
+        let selrect = skia::IRect::from_ltrb(10, 10, 90, 90);
+        let ink = |align| {
+            let ink = render_text(&tree_with_text(&["MMM"], align, "testfont", 1.2)).unwrap();
+            assert!(selrect.contains(ink), "{:?} {:?}", align, ink);
+            ink
+        };
+
+        let left = ink(TextAlign::Left);
+        assert!(left.left < 15 && left.right < 60);
+        let right = ink(TextAlign::Right);
+        assert!(right.right > 85 && right.left > 40);
+        let center = ink(TextAlign::Center);
+        assert!(((center.left + center.right) / 2 - 50).abs() <= 2);
+        // Only the horizontal position depends on the alignment
+        assert_eq!((left.top, left.bottom), (center.top, center.bottom));
+        assert!((left.width() - right.width()).abs() <= 1);
+    }
+
+    #[test]
+    fn texts_fall_back_to_the_default_font_and_grow_with_their_line_height() {
+        let fallback = render_text(&tree_with_text(&["MMM"], TextAlign::Left, "missing", 1.));
+        assert!(fallback.is_some_and(|ink| ink.top >= 10 && ink.bottom <= 40));
+
+        let lines = ["MMM", "MMM"];
+        let tight = render_text(&tree_with_text(&lines, TextAlign::Left, "testfont", 1.)).unwrap();
+        let loose = render_text(&tree_with_text(&lines, TextAlign::Left, "testfont", 2.)).unwrap();
+        // Each line takes the line height times the font size
+        assert!(loose.bottom - tight.bottom >= 16 - 2);
+    }
+
+    #[test]
+    fn masked_groups_keep_their_children_where_the_mask_is_painted() {
+        let (group_id, mask_id, content_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut group = Shape::new(group_id);
+        group.set_kind(Kind::Group(Group::new(true)));
+        group.add_child(mask_id);
+        group.add_child(content_id);
+        // Only the alpha of the mask is used
+        let mut mask = Shape::new(mask_id);
+        mask.set_selrect(0., 0., 50., 100.);
+        mask.add_fill(Fill::Solid(skia::Color::from_argb(128, 255, 0, 0)));
+        let mut content = Shape::new(content_id);
+        content.set_selrect(0., 0., 100., 100.);
+        content.add_fill(Fill::Solid(skia::Color::BLUE));
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(group_id);
+        let tree = HashMap::from([
+            (Uuid::nil(), root),
+            (group_id, group),
+            (mask_id, mask),
+            (content_id, content),
+        ]);
+
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.render_all(&tree, true).unwrap();
+
+        let masked = pixel_at(&mut render_state, 25, 50);
+        assert!((127..=129).contains(&masked.a()));
+        assert_eq!((masked.r(), masked.g()), (0, 0));
+        assert!(masked.b() >= 254);
+        assert_eq!(
+            pixel_at(&mut render_state, 75, 50),
+            skia::Color::TRANSPARENT
+        );
+    }
+
+    #[test]
+    fn background_blurs_in_groups_blur_the_board_beneath_them() {
+        let (board_id, group_id, glass_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut board = frame(0., 0., 50., 100.);
+        board.id = board_id;
+        board.add_fill(Fill::Solid(skia::Color::RED));
+        let mut group = Shape::new(group_id);
+        group.set_kind(Kind::Group(Group::default()));
+        group.add_child(glass_id);
+        let mut glass = Shape::new(glass_id);
+        glass.set_selrect(20., 0., 80., 100.);
+        glass.set_blur(2, false, 10.);
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(board_id);
+        root.add_child(group_id);
+        let tree = HashMap::from([
+            (Uuid::nil(), root),
+            (board_id, board),
+            (group_id, group),
+            (glass_id, glass),
+        ]);
+
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.render_all(&tree, true).unwrap();
+
+        // The edge of the board is blurred under the glass, but not out of it
+        let inside = pixel_at(&mut render_state, 55, 50);
+        assert!(inside.a() > 0 && inside.r() > 0);
+        let outside = pixel_at(&mut render_state, 10, 50);
+        assert_eq!(outside, skia::Color::RED);
+    }
+
+    #[test]
+    fn shapes_are_recorded_once_and_replayed_while_panning() {
+        let tree = tree_with_rect(610., 10., 650., 50.);
+        let id = tree[&Uuid::nil()].children_ids()[0];
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.viewbox.set_pan_xy(-580., 0.);
+        render_state.render_all(&tree, true).unwrap();
+        let picture = render_state
+            .cache
+            .get(&id)
+            .picture(PictureLayer::Content, 1.)
+            .unwrap();
+
+        render_state.viewbox.set_pan_xy(-600., 0.);
+        render_state.pan(&tree).unwrap();
+
+        assert_eq!(pixel_at(&mut render_state, 30, 30), skia::Color::RED);
+        let replayed = render_state
+            .cache
+            .get(&id)
+            .picture(PictureLayer::Content, 1.)
+            .unwrap();
+        assert_eq!(replayed.unique_id(), picture.unique_id());
+    }
+
+    #[test]
+    fn pictures_are_kept_across_zoom_levels_and_exports() {
+        let tree = tree_with_rect(10., 10., 50., 50.);
+        let id = tree[&Uuid::nil()].children_ids()[0];
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.render_all(&tree, true).unwrap();
+        let picture = render_state
+            .cache
+            .get(&id)
+            .picture(PictureLayer::Content, 1.)
+            .unwrap();
+
+        render_state.viewbox.set_zoom(2.);
+        render_state.render_all(&tree, true).unwrap();
+        render_state
+            .export_shape(&id, &tree, 3., ExportFormat::Png, 100)
+            .unwrap();
+
+        let cache = render_state.cache.get(&id);
+        let kept = cache.picture(PictureLayer::Content, 1.).unwrap();
+        assert_eq!(kept.unique_id(), picture.unique_id());
+        assert!(cache.picture(PictureLayer::Content, 2.).is_some());
+        assert!(cache.picture(PictureLayer::Content, 3.).is_none());
+    }
+
+    #[test]
+    fn rendering_a_damaged_area_keeps_the_rest_of_the_cached_tiles() {
+        let mut tree = tree_with_rect(10., 10., 30., 30.);
+        let other_id = Uuid::new_v4();
+        let mut other = Shape::new(other_id);
+        other.set_selrect(60., 60., 80., 80.);
+        other.add_fill(Fill::Solid(skia::Color::RED));
+        tree.insert(other_id, other);
+        tree.get_mut(&Uuid::nil()).unwrap().add_child(other_id);
+
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+        render_state.render_all(&tree, true).unwrap();
+
+        for shape in tree.values_mut() {
+            shape.clear_fills();
+            shape.add_fill(Fill::Solid(skia::Color::BLUE));
+            render_state.cache.invalidate(&shape.id);
         }
+        render_state
+            .render_damage(&tree, math::Rect::from_xywh(0., 0., 40., 40.))
+            .unwrap();
+
+        assert_eq!(pixel_at(&mut render_state, 20, 20), skia::Color::BLUE);
+        assert_eq!(pixel_at(&mut render_state, 70, 70), skia::Color::RED);
+    }
+
+    #[test]
+    fn export_shape_encodes_its_visual_bounds_at_the_given_scale() {
+        let tree = tree_with_rect(10., 10., 50., 50.);
+        let id = tree[&Uuid::nil()].children_ids()[0];
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+
+        let bytes = render_state
+            .export_shape(&id, &tree, 2., ExportFormat::Png, 100)
+            .unwrap();
+        let image = skia::Image::from_encoded(skia::Data::new_copy(&bytes)).unwrap();
+
+        assert_eq!((image.width(), image.height()), (80, 80));
+    }
+
+    #[test]
+    fn export_pdf_writes_a_page_per_frame() {
+        let tree = tree_with_rect(10., 10., 50., 50.);
+        let id = tree[&Uuid::nil()].children_ids()[0];
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+
+        let bytes = render_state.export_pdf(&[id, id], &tree).unwrap();
+        let pdf = String::from_utf8_lossy(&bytes);
+
+        assert!(pdf.starts_with("%PDF"));
+        assert!(pdf.contains("/Count 2"));
+    }
+
+    #[test]
+    fn export_svg_draws_shapes_as_svg_elements() {
+        let tree = tree_with_rect(10., 10., 50., 50.);
+        let id = tree[&Uuid::nil()].children_ids()[0];
+        let mut render_state = RenderState::new_raster(100, 100).unwrap();
+
+        let svg = render_state.export_svg(&id, &tree, false).unwrap();
+
+        assert!(svg.contains("<svg"));
+        assert!(svg.contains("<rect"));
     }
 }
@@ -0,0 +1,33 @@
+use skia_safe as skia;
+
+use super::gpu_state::GpuState;
+
+/// Where the final surface of a [`super::RenderState`] lives.
+pub enum Backend {
+    /// A WebGL framebuffer, used by the browser.
+    Gpu(GpuState),
+    /// A CPU raster surface, used to render without a GL context (workers,
+    /// servers and tests).
+    Raster,
+}
+
+impl Backend {
+    pub fn create_target_surface(
+        &mut self,
+        width: i32,
+        height: i32,
+    ) -> Result<skia::Surface, String> {
+        match self {
+            Self::Gpu(gpu_state) => gpu_state.create_target_surface(width, height),
+            Self::Raster => skia::surfaces::raster_n32_premul((width, height))
+                .ok_or_else(|| format!("Could not create a {}x{} raster surface", width, height)),
+        }
+    }
+
+    pub fn flush_and_submit(&mut self, surface: &mut skia::Surface) {
+        // Raster surfaces are drawn synchronously, so there is nothing to submit
+        if let Self::Gpu(gpu_state) = self {
+            gpu_state.context.flush_and_submit_surface(surface, None);
+        }
+    }
+}
@@ -11,7 +11,7 @@ impl Default for BlendMode {
 
 impl From<i32> for BlendMode {
     fn from(value: i32) -> Self {
-        if value <= skia::BlendMode::Luminosity as i32 {
+        if (0..=skia::BlendMode::Luminosity as i32).contains(&value) {
             unsafe { Self(std::mem::transmute(value)) }
         } else {
             Self::default()
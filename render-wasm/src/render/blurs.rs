@@ -0,0 +1,28 @@
+use skia_safe as skia;
+
+use super::RenderState;
+use crate::shapes::Shape;
+
+/// Blurs what has already been composited beneath the shape, inside its geometry.
+pub fn render_background_blur(
+    render_state: &mut RenderState,
+    shape: &Shape,
+    filter: &skia::ImageFilter,
+) {
+    let path = shape.to_world_path().unwrap_or_else(|| {
+        skia::Path::rect(shape.bounds(), None).with_transform(&shape.centered_transform())
+    });
+
+    // The final canvas is not transformed, so we need to map the geometry
+    // to device space with the current matrix of the drawing canvas.
+    let matrix = render_state.drawing_canvas().local_to_device_as_3x3();
+    let device_path = path.with_transform(&matrix);
+
+    let canvas = render_state.final_canvas();
+    canvas.save();
+    canvas.clip_path(&device_path, skia::ClipOp::Intersect, true);
+    let layer_rec = skia::canvas::SaveLayerRec::default().backdrop(filter);
+    canvas.save_layer(&layer_rec);
+    canvas.restore();
+    canvas.restore();
+}
@@ -1,19 +1,141 @@
-use super::{Image, Viewbox};
-use skia::Contains;
 use skia_safe as skia;
+use std::collections::HashMap;
+use uuid::Uuid;
 
-pub(crate) struct CachedSurfaceImage {
-    pub image: Image,
-    pub viewbox: Viewbox,
-    pub has_all_shapes: bool,
+use crate::shapes::{compute_bool_path, Fill, Kind, Path, Shape};
+
+/// Parts of a shape recorded apart, as frames draw their strokes above their children.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PictureLayer {
+    // Everything but the drop shadows, and the strokes of frames
+    Content,
+    Strokes,
+}
+
+// Pictures of a layer are kept at this many scales, so zooming back and forth replays them
+const MAX_PICTURE_SCALES: usize = 3;
+
+/// Artifacts built out of a shape to render it, kept between frames until the shape changes.
+#[derive(Default)]
+pub struct ShapeCache {
+    svg: Option<skia::svg::Dom>,
+    bool_path: Option<Path>,
+    // Path of `Kind::Path` and `Kind::Bool` shapes, already transformed
+    path: Option<skia::Path>,
+    // Paints of the fills, by their index, as gradients build a shader out of them
+    fill_paints: HashMap<usize, skia::Paint>,
+    // Recorded layers of the shape by the scale they were recorded at, least recently used first
+    pictures: HashMap<PictureLayer, Vec<(f32, skia::Picture)>>,
 }
 
-impl CachedSurfaceImage {
-    pub fn is_dirty_for_zooming(&mut self, viewbox: &Viewbox) -> bool {
-        !self.has_all_shapes && !self.viewbox.area.contains(viewbox.area)
+impl ShapeCache {
+    /// Returns the parsed content of a `Kind::SVGRaw` shape.
+    pub fn svg(
+        &mut self,
+        content: &str,
+        font_manager: skia::FontMgr,
+    ) -> Result<skia::svg::Dom, String> {
+        if let Some(dom) = &self.svg {
+            return Ok(dom.clone());
+        }
+        let dom = skia::svg::Dom::from_str(content, font_manager)
+            .map_err(|e| format!("Error parsing SVG: {}", e))?;
+        self.svg = Some(dom.clone());
+        Ok(dom)
+    }
+
+    /// Returns the result of a bool shape, computed out of its children.
+    pub fn bool_path(&mut self, shape: &Shape, tree: &HashMap<Uuid, Shape>) -> Option<Path> {
+        if self.bool_path.is_none() {
+            self.bool_path = compute_bool_path(shape, tree).map(Path::from);
+        }
+        self.bool_path.clone()
+    }
+
+    /// Returns the path of the shape with its transform applied, or `None` if it is not a path
+    /// or the transform is not invertible.
+    pub fn path(&mut self, shape: &Shape) -> Option<skia::Path> {
+        if self.path.is_none() {
+            let path = match &shape.kind {
+                Kind::Path(path) | Kind::Bool(_, path) => path,
+                _ => return None,
+            };
+            let mut skia_path = path.to_skia_path();
+            skia_path.transform(&shape.to_path_transform()?);
+            if let Some("evenodd") = shape.svg_attrs.get("fill-rule").map(String::as_str) {
+                skia_path.set_fill_type(skia::PathFillType::EvenOdd);
+            }
+            self.path = Some(skia_path);
+        }
+        self.path.clone()
+    }
+
+    pub fn fill_paint(&mut self, index: usize, fill: &Fill, shape: &Shape) -> skia::Paint {
+        self.fill_paints
+            .entry(index)
+            .or_insert_with(|| fill.to_paint(&shape.selrect))
+            .clone()
+    }
+
+    /// Returns the given layer of the shape if it was recorded at the given scale.
+    pub fn picture(&mut self, layer: PictureLayer, scale: f32) -> Option<skia::Picture> {
+        let pictures = self.pictures.get_mut(&layer)?;
+        let index = pictures
+            .iter()
+            .position(|(picture_scale, _)| *picture_scale == scale)?;
+        let entry = pictures.remove(index);
+        let picture = entry.1.clone();
+        pictures.push(entry);
+        Some(picture)
+    }
+
+    /// Keeps the given layer of the shape, dropping the one recorded at the least recently used
+    /// scale when there are too many of them.
+    pub fn set_picture(&mut self, layer: PictureLayer, scale: f32, picture: skia::Picture) {
+        let pictures = self.pictures.entry(layer).or_default();
+        pictures.retain(|(picture_scale, _)| *picture_scale != scale);
+        if pictures.len() >= MAX_PICTURE_SCALES {
+            pictures.remove(0);
+        }
+        pictures.push((scale, picture));
+    }
+}
+
+/// Render artifacts of every shape, see [ShapeCache]. The state invalidates the entries of the
+/// shapes it changes.
+#[derive(Default)]
+pub struct RenderCache {
+    shapes: HashMap<Uuid, ShapeCache>,
+}
+
+impl RenderCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&mut self, id: &Uuid) -> &mut ShapeCache {
+        self.shapes.entry(*id).or_default()
+    }
+
+    pub fn contains(&self, id: &Uuid) -> bool {
+        self.shapes.contains_key(id)
+    }
+
+    pub fn invalidate(&mut self, id: &Uuid) {
+        self.shapes.remove(id);
+    }
+
+    /// Drops the result of a bool shape, whose children have changed.
+    pub fn invalidate_bool_path(&mut self, id: &Uuid) {
+        if let Some(cache) = self.shapes.get_mut(id) {
+            if cache.bool_path.take().is_some() {
+                cache.path = None;
+                cache.pictures.clear();
+            }
+        }
     }
 
-    pub fn is_dirty_for_panning(&mut self, _viewbox: &Viewbox) -> bool {
-        !self.has_all_shapes
+    pub fn clear(&mut self) {
+        self.shapes.clear();
     }
 }
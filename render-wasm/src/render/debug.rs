@@ -31,12 +31,12 @@ pub fn render_wasm_label(render_state: &mut RenderState) {
     paint.set_color(skia::Color::from_argb(100, 0, 0, 0));
 
     let font_provider = &render_state.font_provider;
-    let typeface = font_provider
-        .match_family_style("robotomono-regular", skia::FontStyle::default())
-        .unwrap();
-
-    let font = skia::Font::new(typeface, 10.0);
-    canvas.draw_str("WASM RENDERER", p, &font, &paint);
+    if let Some(typeface) =
+        font_provider.match_family_style("robotomono-regular", skia::FontStyle::default())
+    {
+        let font = skia::Font::new(typeface, 10.0);
+        canvas.draw_str("WASM RENDERER", p, &font, &paint);
+    }
 }
 
 pub fn render_debug_element(render_state: &mut RenderState, element: &Shape, intersected: bool) {
@@ -49,7 +49,7 @@ pub fn render_debug_element(render_state: &mut RenderState, element: &Shape, int
     });
     paint.set_stroke_width(1.);
 
-    let mut scaled_rect = element.bounds();
+    let mut scaled_rect = element.visual_bounds();
     let x = 100. + scaled_rect.x() * 0.2;
     let y = 100. + scaled_rect.y() * 0.2;
     let width = scaled_rect.width() * 0.2;
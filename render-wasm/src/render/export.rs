@@ -0,0 +1,118 @@
+use skia_safe as skia;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use super::{RenderCache, RenderState};
+use crate::shapes::{subtree_visual_bounds, Shape};
+use crate::view::Viewbox;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl From<u8> for ExportFormat {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Jpeg,
+            2 => Self::Webp,
+            _ => Self::Png,
+        }
+    }
+}
+
+impl ExportFormat {
+    fn encoded_image_format(&self) -> Result<skia::EncodedImageFormat, String> {
+        match self {
+            Self::Png => Ok(skia::EncodedImageFormat::PNG),
+            Self::Jpeg => Ok(skia::EncodedImageFormat::JPEG),
+            #[cfg(feature = "webp")]
+            Self::Webp => Ok(skia::EncodedImageFormat::WEBP),
+            #[cfg(not(feature = "webp"))]
+            Self::Webp => Err("This build does not support WebP encoding".to_string()),
+        }
+    }
+}
+
+/// Renders the subtree rooted at the given shape into an offscreen surface and encodes it.
+/// The on-screen surfaces, viewbox and options are left untouched.
+pub fn export_shape(
+    render_state: &mut RenderState,
+    id: &Uuid,
+    tree: &HashMap<Uuid, Shape>,
+    scale: f32,
+    format: ExportFormat,
+    quality: u32,
+) -> Result<Vec<u8>, String> {
+    let encoded_format = format.encoded_image_format()?;
+    let bounds = subtree_visual_bounds(id, tree).ok_or("Shape not found or hidden")?;
+
+    let width = (bounds.width() * scale).ceil() as i32;
+    let height = (bounds.height() * scale).ceil() as i32;
+    if width <= 0 || height <= 0 {
+        return Err("Shape has an empty area".to_string());
+    }
+
+    let new_surface = || {
+        skia::surfaces::raster_n32_premul((width, height))
+            .ok_or("Could not create export surface".to_string())
+    };
+    let mut final_surface = new_surface()?;
+    let mut drawing_surface = new_surface()?;
+    let mut shadow_surface = new_surface()?;
+    let mut debug_surface = new_surface()?;
+
+    // JPEG has no alpha channel, so transparent areas would end up black
+    let background = match format {
+        ExportFormat::Jpeg => skia::Color::WHITE,
+        _ => skia::Color::TRANSPARENT,
+    };
+    final_surface.canvas().clear(background);
+    drawing_surface.canvas().clear(skia::Color::TRANSPARENT);
+    shadow_surface.canvas().clear(skia::Color::TRANSPARENT);
+    debug_surface.canvas().clear(skia::Color::TRANSPARENT);
+
+    drawing_surface.canvas().scale((scale, scale));
+    drawing_surface
+        .canvas()
+        .translate((-bounds.left, -bounds.top));
+
+    std::mem::swap(&mut render_state.final_surface, &mut final_surface);
+    std::mem::swap(&mut render_state.drawing_surface, &mut drawing_surface);
+    std::mem::swap(&mut render_state.shadow_surface, &mut shadow_surface);
+    std::mem::swap(&mut render_state.debug_surface, &mut debug_surface);
+
+    let viewbox = render_state.viewbox;
+    let options = render_state.options;
+    render_state.viewbox = Viewbox {
+        pan_x: -bounds.left,
+        pan_y: -bounds.top,
+        width: width as f32,
+        height: height as f32,
+        zoom: scale,
+        area: bounds,
+    };
+    render_state.options.dpr = None;
+    // The pictures recorded at the export scale must not replace the on-screen ones
+    let cache = std::mem::replace(&mut render_state.cache, RenderCache::new());
+
+    let result = render_state.render_shape_tree(id, tree);
+    let image = render_state.final_surface.image_snapshot();
+
+    render_state.cache = cache;
+    render_state.viewbox = viewbox;
+    render_state.options = options;
+    std::mem::swap(&mut render_state.final_surface, &mut final_surface);
+    std::mem::swap(&mut render_state.drawing_surface, &mut drawing_surface);
+    std::mem::swap(&mut render_state.shadow_surface, &mut shadow_surface);
+    std::mem::swap(&mut render_state.debug_surface, &mut debug_surface);
+    result?;
+
+    let data = image
+        .encode(None, encoded_format, quality)
+        .ok_or("Could not encode the exported image")?;
+
+    Ok(data.as_bytes().to_vec())
+}
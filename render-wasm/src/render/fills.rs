@@ -1,64 +1,34 @@
-use crate::{
-    math,
-    shapes::{Fill, ImageFill, Kind, Shape},
-};
+use crate::shapes::{Fill, ImageFill, Kind, Shape};
 use skia_safe::{self as skia, RRect};
 
-use super::RenderState;
+use super::{images, RenderState};
 
 fn draw_image_fill_in_container(
     render_state: &mut RenderState,
     shape: &Shape,
+    index: usize,
     fill: &Fill,
     image_fill: &ImageFill,
 ) {
-    let image = render_state.images.get(&image_fill.id());
-    if image.is_none() {
-        return;
-    }
-
-    let size = image_fill.size();
-    let canvas = render_state.drawing_surface.canvas();
-    let kind = &shape.kind;
-    let container = &shape.selrect;
-    let path_transform = shape.to_path_transform();
-    let paint = fill.to_paint(container);
-
-    let width = size.0 as f32;
-    let height = size.1 as f32;
-    let image_aspect_ratio = width / height;
-
-    // Container size
-    let container_width = container.width();
-    let container_height = container.height();
-    let container_aspect_ratio = container_width / container_height;
-
-    // Calculate scale to ensure the image covers the container
-    let scale = if image_aspect_ratio > container_aspect_ratio {
-        // Image is wider, scale based on height to cover container
-        container_height / height
-    } else {
-        // Image is taller, scale based on width to cover container
-        container_width / width
+    let image = match render_state.images.get(&image_fill.id()).cloned() {
+        Some(image) => image,
+        None => return,
     };
 
-    // Scaled size of the image
-    let scaled_width = width * scale;
-    let scaled_height = height * scale;
+    let cache = render_state.cache.get(&shape.id);
+    let paint = cache.fill_paint(index, fill, shape);
+    let path = cache.path(shape);
 
-    let dest_rect = math::Rect::from_xywh(
-        container.left - (scaled_width - container_width) / 2.0,
-        container.top - (scaled_height - container_height) / 2.0,
-        scaled_width,
-        scaled_height,
-    );
+    let canvas = render_state.drawing_canvas();
+    let kind = &shape.kind;
+    let container = &shape.selrect;
 
     // Save the current canvas state
     canvas.save();
 
     // Set the clipping rectangle to the container bounds
     match kind {
-        Kind::Rect(_, _) => {
+        Kind::Rect(_, _) | Kind::Frame(_, _) => {
             canvas.clip_rect(container, skia::ClipOp::Intersect, true);
         }
         Kind::Circle(_) => {
@@ -66,20 +36,24 @@ fn draw_image_fill_in_container(
             oval_path.add_oval(container, None);
             canvas.clip_path(&oval_path, skia::ClipOp::Intersect, true);
         }
-        Kind::Path(path) | Kind::Bool(_, path) => {
-            canvas.clip_path(
-                &path.to_skia_path().transform(&path_transform.unwrap()),
-                skia::ClipOp::Intersect,
-                true,
-            );
+        Kind::Path(_) | Kind::Bool(_, _) => {
+            // Paths with a non-invertible transform have no area
+            let path = match path {
+                Some(path) => path,
+                None => {
+                    canvas.restore();
+                    return;
+                }
+            };
+            canvas.clip_path(&path, skia::ClipOp::Intersect, true);
         }
-        Kind::SVGRaw(_) => {
+        Kind::SVGRaw(_) | Kind::Text(_) | Kind::Group(_) => {
             canvas.clip_rect(container, skia::ClipOp::Intersect, true);
         }
     }
 
-    // Draw the image with the calculated destination rectangle
-    canvas.draw_image_rect(image.unwrap(), None, dest_rect, &paint);
+    // Draw the image according to its scale mode
+    images::draw_image_fill(canvas, &image, image_fill, container, &paint);
 
     // Restore the canvas to remove the clipping
     canvas.restore();
@@ -88,34 +62,35 @@ fn draw_image_fill_in_container(
 /**
  * This SHOULD be the only public function in this module.
  */
-pub fn render(render_state: &mut RenderState, shape: &Shape, fill: &Fill) {
-    let canvas = render_state.drawing_surface.canvas();
-    let selrect = shape.selrect;
-    let path_transform = shape.to_path_transform();
-    let kind = &shape.kind;
-    match (fill, kind) {
-        (Fill::Image(image_fill), _) => {
-            draw_image_fill_in_container(render_state, shape, fill, image_fill);
-        }
-        (_, Kind::Rect(rect, None)) => {
-            canvas.draw_rect(rect, &fill.to_paint(&selrect));
+pub fn render(render_state: &mut RenderState, shape: &Shape, index: usize, fill: &Fill) {
+    if let Fill::Image(image_fill) = fill {
+        draw_image_fill_in_container(render_state, shape, index, fill, image_fill);
+        return;
+    }
+
+    let cache = render_state.cache.get(&shape.id);
+    let paint = cache.fill_paint(index, fill, shape);
+    let path = cache.path(shape);
+
+    let canvas = render_state.drawing_canvas();
+    match &shape.kind {
+        Kind::Rect(rect, None) | Kind::Frame(rect, None) => {
+            canvas.draw_rect(rect, &paint);
         }
-        (_, Kind::Rect(rect, Some(corners))) => {
+        Kind::Rect(rect, Some(corners)) | Kind::Frame(rect, Some(corners)) => {
             let rrect = RRect::new_rect_radii(rect, &corners);
-            canvas.draw_rrect(rrect, &fill.to_paint(&selrect));
+            canvas.draw_rrect(rrect, &paint);
         }
-        (_, Kind::Circle(rect)) => {
-            canvas.draw_oval(rect, &fill.to_paint(&selrect));
+        Kind::Circle(rect) => {
+            canvas.draw_oval(rect, &paint);
         }
-        (_, Kind::Path(path)) | (_, Kind::Bool(_, path)) => {
-            let svg_attrs = &shape.svg_attrs;
-            let mut skia_path = &mut path.to_skia_path();
-            skia_path = skia_path.transform(&path_transform.unwrap());
-            if let Some("evenodd") = svg_attrs.get("fill-rule").map(String::as_str) {
-                skia_path.set_fill_type(skia::PathFillType::EvenOdd);
+        // Paths with a non-invertible transform have no area
+        Kind::Path(_) | Kind::Bool(_, _) => {
+            if let Some(path) = path {
+                canvas.draw_path(&path, &paint);
             }
-            canvas.draw_path(&skia_path, &fill.to_paint(&selrect));
         }
-        (_, _) => todo!(),
+        // These kinds paint their own content
+        Kind::SVGRaw(_) | Kind::Text(_) | Kind::Group(_) => {}
     }
 }
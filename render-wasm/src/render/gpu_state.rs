@@ -7,28 +7,34 @@ pub struct GpuState {
 }
 
 impl GpuState {
-    pub fn new() -> Self {
-        let interface = gpu::gl::Interface::new_native().unwrap();
-        let context = gpu::direct_contexts::make_gl(interface, None).unwrap();
+    pub fn new() -> Result<Self, String> {
+        let interface =
+            gpu::gl::Interface::new_native().ok_or("Could not create a GL interface")?;
+        let context = gpu::direct_contexts::make_gl(interface, None)
+            .ok_or("Could not create a GL context")?;
         let framebuffer_info = {
             let mut fboid: gl::types::GLint = 0;
             unsafe { gl::GetIntegerv(gl::FRAMEBUFFER_BINDING, &mut fboid) };
 
             FramebufferInfo {
-                fboid: fboid.try_into().unwrap(),
+                fboid: fboid.try_into().map_err(|_| "Invalid framebuffer")?,
                 format: gpu::gl::Format::RGBA8.into(),
                 protected: gpu::Protected::No,
             }
         };
 
-        GpuState {
+        Ok(GpuState {
             context,
             framebuffer_info,
-        }
+        })
     }
 
     /// Create a Skia surface that will be used for rendering.
-    pub fn create_target_surface(&mut self, width: i32, height: i32) -> skia::Surface {
+    pub fn create_target_surface(
+        &mut self,
+        width: i32,
+        height: i32,
+    ) -> Result<skia::Surface, String> {
         let backend_render_target =
             gpu::backend_render_targets::make_gl((width, height), 1, 8, self.framebuffer_info);
 
@@ -40,6 +46,6 @@ impl GpuState {
             None,
             None,
         )
-        .unwrap()
+        .ok_or_else(|| format!("Could not create a {}x{} GL surface", width, height))
     }
 }
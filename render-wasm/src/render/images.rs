@@ -1,33 +1,197 @@
 use skia_safe as skia;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use uuid::Uuid;
 
+use crate::math;
+use crate::shapes::{ImageFill, ImageScaleMode};
+
 pub type Image = skia::Image;
 
+/// Decoded images are kept until they take more than this many bytes.
+pub const DEFAULT_IMAGE_BUDGET: usize = 256 * 1024 * 1024;
+
+/// Decoded images, evicting the least recently rendered ones when they exceed the byte budget.
+/// JS has to send an evicted image again before it can be rendered.
 pub struct ImageStore {
-    images: HashMap<Uuid, Image>,
+    // Each image with the tick it was last rendered at
+    images: HashMap<Uuid, (Image, u64)>,
+    // Images by the tick they were last rendered at, least recently rendered first
+    lru: BTreeMap<u64, Uuid>,
+    tick: u64,
+    budget: usize,
+    used_bytes: usize,
 }
 
 impl ImageStore {
-    pub fn new() -> Self {
+    pub fn new(budget: usize) -> Self {
         Self {
-            images: HashMap::with_capacity(2048),
+            images: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            budget,
+            used_bytes: 0,
         }
     }
 
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict();
+    }
+
     pub fn add(&mut self, id: Uuid, image_data: &[u8]) -> Result<(), String> {
         let image_data = skia::Data::new_copy(image_data);
         let image = Image::from_encoded(image_data).ok_or("Error decoding image data")?;
 
-        self.images.insert(id, image);
+        self.remove(&id);
+        self.used_bytes += byte_size(&image);
+        let tick = self.next_tick();
+        self.images.insert(id, (image, tick));
+        self.lru.insert(tick, id);
+        self.evict();
         Ok(())
     }
 
-    pub fn contains(&mut self, id: &Uuid) -> bool {
+    pub fn contains(&self, id: &Uuid) -> bool {
         self.images.contains_key(id)
     }
 
-    pub fn get(&self, id: &Uuid) -> Option<&Image> {
-        self.images.get(id)
+    /// Returns the image to render it, so it becomes the most recently rendered one.
+    pub fn get(&mut self, id: &Uuid) -> Option<&Image> {
+        let tick = self.next_tick();
+        let (image, last_use) = self.images.get_mut(id)?;
+        self.lru.remove(last_use);
+        self.lru.insert(tick, *id);
+        *last_use = tick;
+        Some(image)
+    }
+
+    pub fn remove(&mut self, id: &Uuid) {
+        if let Some((image, last_use)) = self.images.remove(id) {
+            self.used_bytes -= byte_size(&image);
+            self.lru.remove(&last_use);
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    // The most recent image is kept even if it does not fit in the budget by itself
+    fn evict(&mut self) {
+        while self.used_bytes > self.budget && self.lru.len() > 1 {
+            if let Some((_, id)) = self.lru.pop_first() {
+                self.remove(&id);
+            }
+        }
+    }
+}
+
+// Size of the decoded pixels, which is what the image takes once it has been rendered.
+fn byte_size(image: &Image) -> usize {
+    image.width() as usize * image.height() as usize * 4
+}
+
+/// Draws the image of an image fill inside the given container, according to its scale mode.
+/// Clipping to the shape is up to the caller.
+pub fn draw_image_fill(
+    canvas: &skia::Canvas,
+    image: &Image,
+    image_fill: &ImageFill,
+    container: &math::Rect,
+    paint: &skia::Paint,
+) {
+    let dest_rect = image_fill.dest_rect(container);
+    // Fills with an empty size have nowhere to place the image
+    if dest_rect.is_empty() || !dest_rect.is_finite() {
+        return;
+    }
+
+    if image_fill.scale_mode() != ImageScaleMode::Tile {
+        canvas.draw_image_rect(image, None, dest_rect, paint);
+        return;
+    }
+
+    let mut matrix = skia::Matrix::translate((dest_rect.left, dest_rect.top));
+    matrix.pre_scale(
+        (
+            dest_rect.width() / image.width() as f32,
+            dest_rect.height() / image.height() as f32,
+        ),
+        None,
+    );
+
+    let shader = image.to_shader(
+        (skia::TileMode::Repeat, skia::TileMode::Repeat),
+        skia::SamplingOptions::default(),
+        &matrix,
+    );
+
+    let mut tile_paint = paint.clone();
+    tile_paint.set_shader(shader);
+    canvas.draw_rect(container, &tile_paint);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_bytes(width: i32, height: i32) -> Vec<u8> {
+        let mut surface = skia::surfaces::raster_n32_premul((width, height)).unwrap();
+        surface
+            .image_snapshot()
+            .encode(None, skia::EncodedImageFormat::PNG, None)
+            .unwrap()
+            .as_bytes()
+            .to_vec()
+    }
+
+    #[test]
+    fn least_recently_rendered_images_are_evicted_over_the_budget() {
+        let (a, b, c) = (Uuid::from_u128(1), Uuid::from_u128(2), Uuid::from_u128(3));
+        // Room for two 10x10 images
+        let mut store = ImageStore::new(2 * 10 * 10 * 4);
+        store.add(a, &png_bytes(10, 10)).unwrap();
+        store.add(b, &png_bytes(10, 10)).unwrap();
+        assert!(store.get(&a).is_some());
+
+        store.add(c, &png_bytes(10, 10)).unwrap();
+        assert!(store.contains(&a));
+        assert!(!store.contains(&b));
+        assert!(store.contains(&c));
+
+        store.set_budget(0);
+        assert!(!store.contains(&a));
+        assert!(store.contains(&c));
+    }
+
+    #[test]
+    fn tiled_fills_with_an_empty_size_paint_nothing() {
+        let fill = match crate::shapes::Fill::new_image_fill(
+            Uuid::nil(),
+            255,
+            (0, 0),
+            ImageScaleMode::Tile,
+            1.,
+        ) {
+            crate::shapes::Fill::Image(image_fill) => image_fill,
+            _ => unreachable!(),
+        };
+        let mut image_surface = skia::surfaces::raster_n32_premul((1, 1)).unwrap();
+        image_surface.canvas().clear(skia::Color::RED);
+        let image = image_surface.image_snapshot();
+
+        let mut surface = skia::surfaces::raster_n32_premul((10, 10)).unwrap();
+        let container = math::Rect::from_xywh(0., 0., 10., 10.);
+        draw_image_fill(
+            surface.canvas(),
+            &image,
+            &fill,
+            &container,
+            &skia::Paint::default(),
+        );
+
+        let pixel = surface.peek_pixels().unwrap().get_color((5, 5));
+        assert_eq!(pixel, skia::Color::TRANSPARENT);
     }
 }
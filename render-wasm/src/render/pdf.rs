@@ -0,0 +1,37 @@
+use skia_safe as skia;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use super::RenderState;
+use crate::shapes::Shape;
+
+/// Renders each of the given frames as a page of a PDF document, keeping shapes and texts as
+/// vectors. Pages have the size of the frames, in points.
+pub fn export_pdf(
+    render_state: &mut RenderState,
+    ids: &[Uuid],
+    tree: &HashMap<Uuid, Shape>,
+) -> Result<Vec<u8>, String> {
+    if ids.is_empty() {
+        return Err("No frames to export".to_string());
+    }
+
+    let mut bytes = vec![];
+    let mut document = skia::pdf::new_document(&mut bytes, None);
+
+    for id in ids {
+        let shape = tree.get(id).ok_or(format!("Frame {id} not found"))?;
+        let (area, _) = shape.centered_transform().map_rect(shape.bounds());
+        let picture = render_state.record_shape_tree(id, tree, area)?;
+
+        let mut page = document.begin_page((area.width(), area.height()), None);
+        let canvas = page.canvas();
+        canvas.translate((-area.left, -area.top));
+        canvas.clip_rect(area, skia::ClipOp::Intersect, true);
+        canvas.draw_picture(&picture, None, None);
+        document = page.end_page();
+    }
+
+    document.close();
+    Ok(bytes)
+}
@@ -1,7 +1,7 @@
 use skia_safe::{self as skia};
 
 use super::RenderState;
-use crate::shapes::Shadow;
+use crate::shapes::{Shadow, Shape};
 
 pub fn render_drop_shadow(render_state: &mut RenderState, shadow: &Shadow, scale: f32) {
     let shadow_paint = shadow.to_paint(true, scale);
@@ -23,3 +23,39 @@ pub fn render_drop_shadow(render_state: &mut RenderState, shadow: &Shadow, scale
         .canvas()
         .clear(skia::Color::TRANSPARENT);
 }
+
+/// Draws the drop shadows of a shape out of its recorded content, when rendering vectors.
+pub fn render_vector_drop_shadows(
+    render_state: &mut RenderState,
+    shape: &Shape,
+    content: &skia::Picture,
+) {
+    let scale = render_state.viewbox.zoom * render_state.options.dpr();
+    let canvas = render_state.drawing_canvas();
+    for shadow in shape.drop_shadows().rev().filter(|s| !s.hidden()) {
+        canvas.draw_picture(content, None, Some(&shadow.to_paint(true, scale)));
+    }
+}
+
+/// Draws the recorded fills of a shape with its inner shadows on top, while recording its content.
+pub fn render_vector_inner_shadows(
+    render_state: &mut RenderState,
+    shape: &Shape,
+    fills: &skia::Picture,
+) {
+    // The fills have been recorded with the shape transform already applied
+    let canvas = render_state.drawing_canvas();
+    canvas.save();
+    canvas.reset_matrix();
+    canvas.draw_picture(fills, None, None);
+    // The content is replayed with the transform of the canvas, which scales the filters too
+    let paints = shape
+        .inner_shadows()
+        .rev()
+        .filter(|s| !s.hidden())
+        .filter_map(|s| s.to_inner_paint(1.));
+    for paint in paints {
+        canvas.draw_picture(fills, None, Some(&paint));
+    }
+    canvas.restore();
+}
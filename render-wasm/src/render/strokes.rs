@@ -1,10 +1,10 @@
 use std::collections::HashMap;
 
-use crate::math::{self, Rect};
+use crate::math::Rect;
 use crate::shapes::{Corners, Fill, ImageFill, Kind, Path, Shape, Stroke, StrokeCap, StrokeKind};
 use skia_safe::{self as skia, RRect};
 
-use super::RenderState;
+use super::{images, RenderState};
 
 fn draw_stroke_on_rect(
     canvas: &skia::Canvas,
@@ -22,16 +22,14 @@ fn draw_stroke_on_rect(
     let stroke_rect = stroke.outer_rect(rect);
     let paint = stroke.to_paint(selrect, svg_attrs, scale);
 
-    match corners {
+    let contour = match corners {
         Some(radii) => {
             let radii = stroke.outer_corners(radii);
-            let rrect = RRect::new_rect_radii(stroke_rect, &radii);
-            canvas.draw_rrect(rrect, &paint);
+            skia::Path::rrect(RRect::new_rect_radii(stroke_rect, &radii), None)
         }
-        None => {
-            canvas.draw_rect(&stroke_rect, &paint);
-        }
-    }
+        None => skia::Path::rect(stroke_rect, None),
+    };
+    draw_stroke_path(canvas, stroke, &contour, &paint, svg_attrs);
 }
 
 fn draw_stroke_on_circle(
@@ -47,7 +45,178 @@ fn draw_stroke_on_circle(
     // - A bigger oval if it's an outer stroke
     // - A smaller oval if it's an outer stroke
     let stroke_rect = stroke.outer_rect(rect);
-    canvas.draw_oval(&stroke_rect, &stroke.to_paint(selrect, svg_attrs, scale));
+    let paint = stroke.to_paint(selrect, svg_attrs, scale);
+    draw_stroke_path(
+        canvas,
+        stroke,
+        &skia::Path::oval(stroke_rect, None),
+        &paint,
+        svg_attrs,
+    );
+}
+
+/// Draws a path with the paint of a stroke. When it is dashed, or dotted, the pattern of each
+/// closed contour is fitted to every side between two of its corners, so the corners are painted.
+fn draw_stroke_path(
+    canvas: &skia::Canvas,
+    stroke: &Stroke,
+    path: &skia::Path,
+    paint: &skia::Paint,
+    svg_attrs: &HashMap<String, String>,
+) {
+    if paint.path_effect().is_none() {
+        canvas.draw_path(path, paint);
+        return;
+    }
+    let mut pattern_paint = paint.clone();
+    pattern_paint.set_path_effect(None);
+    for measure in skia::ContourMeasureIter::new(path, false, None) {
+        let mut contour = match measure.segment(0., measure.length(), true) {
+            Some(contour) => contour,
+            None => continue,
+        };
+        if !measure.is_closed() {
+            canvas.draw_path(&contour, paint);
+            continue;
+        }
+        contour.close();
+        let pattern = closed_contour_pattern(&measure, &contour, stroke, svg_attrs);
+        canvas.draw_path(&pattern, &pattern_paint);
+    }
+}
+
+// Returns the dashes, or the dots, of a closed contour. Each side between two corners gets its
+// own fitted pattern, and contours without corners get a single one fitted around them.
+fn closed_contour_pattern(
+    measure: &skia::ContourMeasure,
+    contour: &skia::Path,
+    stroke: &Stroke,
+    svg_attrs: &HashMap<String, String>,
+) -> skia::Path {
+    let length = measure.length();
+    let mut corners = corner_distances(contour);
+    if corners.is_empty() {
+        corners.push(0.);
+    }
+    let sides = corners.iter().enumerate().map(|(i, &start)| {
+        let end = corners.get(i + 1).copied().unwrap_or(corners[0] + length);
+        (start, end - start)
+    });
+
+    let mut pattern = skia::Path::new();
+    match stroke.dash(svg_attrs) {
+        Some(dash) => {
+            let mut dashes: Vec<(f32, f32)> = vec![];
+            for (start, side_length) in sides {
+                for (dash_start, dash_end) in dash.side_dashes(side_length) {
+                    let (dash_start, dash_end) = (start + dash_start, start + dash_end);
+                    // Dashes ending at a corner continue along the next side
+                    match dashes.last_mut() {
+                        Some(last) if (last.1 - dash_start).abs() < 1e-3 => last.1 = dash_end,
+                        _ => dashes.push((dash_start, dash_end)),
+                    }
+                }
+            }
+            if dashes.len() > 1 {
+                let (first, last) = (dashes[0], dashes[dashes.len() - 1]);
+                if (last.1 - (first.0 + length)).abs() < 1e-3 {
+                    dashes.pop();
+                    dashes[0] = (last.0, first.1 + length);
+                }
+            }
+            for (start, end) in dashes {
+                add_contour_segment(&mut pattern, measure, start, end);
+            }
+        }
+        None => {
+            for (start, side_length) in sides {
+                for distance in stroke.side_dots(side_length) {
+                    if let Some((center, _)) = measure.pos_tan((start + distance) % length) {
+                        pattern.add_path(&stroke.dot_path(center), (0., 0.), None);
+                    }
+                }
+            }
+        }
+    }
+    pattern
+}
+
+// Adds the piece of a closed contour between two distances, which may go past its end
+fn add_contour_segment(
+    path: &mut skia::Path,
+    measure: &skia::ContourMeasure,
+    start: f32,
+    end: f32,
+) {
+    let length = measure.length();
+    let (start, end) = if start >= length {
+        (start - length, end - length)
+    } else {
+        (start, end)
+    };
+    let mut segment = match measure.segment(start, end.min(length), true) {
+        Some(segment) => segment,
+        None => return,
+    };
+    if end > length {
+        if let Some(rest) = measure.segment(0., end - length, true) {
+            segment.add_path(&rest, (0., 0.), skia::path::AddPathMode::Extend);
+        }
+    }
+    path.add_path(&segment, (0., 0.), None);
+}
+
+// Returns the distances along a closed contour where its direction changes
+fn corner_distances(contour: &skia::Path) -> Vec<f32> {
+    let is_corner = |a: skia::Vector, b: skia::Vector| a.dot(b) < 0.999;
+    let mut corners = vec![];
+    let mut distance = 0.;
+    let mut tangents: Option<(skia::Vector, skia::Vector)> = None;
+    let mut verbs = skia::path::Iter::new(contour, true);
+    while let Some((verb, points)) = verbs.next() {
+        let mut segment = skia::Path::new();
+        match verb {
+            skia::path::Verb::Line => segment.move_to(points[0]).line_to(points[1]),
+            skia::path::Verb::Quad => segment.move_to(points[0]).quad_to(points[1], points[2]),
+            skia::path::Verb::Conic => {
+                let weight = verbs.conic_weight().unwrap_or(1.);
+                segment
+                    .move_to(points[0])
+                    .conic_to(points[1], points[2], weight)
+            }
+            skia::path::Verb::Cubic => segment
+                .move_to(points[0])
+                .cubic_to(points[1], points[2], points[3]),
+            // Moves and closes carry no segment, closing lines come as lines
+            _ => continue,
+        };
+        // Segments without length don't change the direction
+        let measure = match skia::ContourMeasureIter::new(&segment, false, None).next() {
+            Some(measure) => measure,
+            None => continue,
+        };
+        let (start, end) = match (measure.pos_tan(0.), measure.pos_tan(measure.length())) {
+            (Some((_, start)), Some((_, end))) => (start, end),
+            _ => continue,
+        };
+        tangents = match tangents {
+            Some((first, last)) => {
+                if is_corner(last, start) {
+                    corners.push(distance);
+                }
+                Some((first, end))
+            }
+            None => Some((start, end)),
+        };
+        distance += measure.length();
+    }
+    // The contour may also turn where it closes
+    if let Some((first, last)) = tangents {
+        if is_corner(last, first) {
+            corners.insert(0, 0.);
+        }
+    }
+    corners
 }
 
 fn draw_stroke_on_path(
@@ -59,8 +228,13 @@ fn draw_stroke_on_path(
     svg_attrs: &HashMap<String, String>,
     scale: f32,
 ) {
+    // Paths with a non-invertible transform have no area
+    let path_transform = match path_transform {
+        Some(path_transform) => path_transform,
+        None => return,
+    };
     let mut skia_path = path.to_skia_path();
-    skia_path.transform(path_transform.unwrap());
+    skia_path.transform(path_transform);
 
     let is_open = path.is_open();
     let paint_stroke = stroke.to_stroked_paint(is_open, selrect, svg_attrs, scale);
@@ -69,11 +243,11 @@ fn draw_stroke_on_path(
         // For inner stroke we draw a center stroke (with double width) and clip to the original path (that way the extra outer stroke is removed)
         StrokeKind::InnerStroke => {
             canvas.clip_path(&skia_path, skia::ClipOp::Intersect, true);
-            canvas.draw_path(&skia_path, &paint_stroke);
+            draw_stroke_path(canvas, stroke, &skia_path, &paint_stroke, svg_attrs);
         }
         // For center stroke we don't need to do anything extra
         StrokeKind::CenterStroke => {
-            canvas.draw_path(&skia_path, &paint_stroke);
+            draw_stroke_path(canvas, stroke, &skia_path, &paint_stroke, svg_attrs);
         }
         // For outer stroke we draw a center stroke (with double width) and use another path with blend mode clear to remove the inner stroke added
         StrokeKind::OuterStroke => {
@@ -83,7 +257,7 @@ fn draw_stroke_on_path(
             let layer_rec = skia::canvas::SaveLayerRec::default().paint(&paint);
             canvas.save_layer(&layer_rec);
 
-            canvas.draw_path(&skia_path, &paint_stroke);
+            draw_stroke_path(canvas, stroke, &skia_path, &paint_stroke, svg_attrs);
 
             let mut clear_paint = skia::Paint::default();
             clear_paint.set_blend_mode(skia::BlendMode::Clear);
@@ -297,50 +471,28 @@ fn draw_triangle_cap(
     canvas.draw_path(&path, paint);
 }
 
-fn calculate_scaled_rect(size: (i32, i32), container: &math::Rect, delta: f32) -> math::Rect {
-    let (width, height) = (size.0 as f32, size.1 as f32);
-    let image_aspect_ratio = width / height;
-
-    // Container size
-    let container_width = container.width();
-    let container_height = container.height();
-    let container_aspect_ratio = container_width / container_height;
-
-    let scale = if image_aspect_ratio > container_aspect_ratio {
-        container_height / height
-    } else {
-        container_width / width
-    };
-
-    let scaled_width = width * scale;
-    let scaled_height = height * scale;
-
-    math::Rect::from_xywh(
-        container.left - delta - (scaled_width - container_width) / 2.0,
-        container.top - delta - (scaled_height - container_height) / 2.0,
-        scaled_width + (2. * delta) + (scaled_width - container_width),
-        scaled_height + (2. * delta) + (scaled_width - container_width),
-    )
-}
-
 fn draw_image_stroke_in_container(
     render_state: &mut RenderState,
     shape: &Shape,
     stroke: &Stroke,
     image_fill: &ImageFill,
 ) {
-    let image = render_state.images.get(&image_fill.id());
-    if image.is_none() {
-        return;
-    }
+    let image = match render_state.images.get(&image_fill.id()).cloned() {
+        Some(image) => image,
+        None => return,
+    };
 
-    let size = image_fill.size();
-    let canvas = render_state.drawing_surface.canvas();
     let kind = &shape.kind;
+    let path_transform = match (kind, shape.to_path_transform()) {
+        // Paths with a non-invertible transform have no area
+        (Kind::Path(_) | Kind::Bool(_, _), None) => return,
+        (_, path_transform) => path_transform.unwrap_or_default(),
+    };
+
+    let dpr_scale = render_state.viewbox.zoom * render_state.options.dpr();
+    let canvas = render_state.drawing_canvas();
     let container = &shape.selrect;
-    let path_transform = shape.to_path_transform();
     let svg_attrs = &shape.svg_attrs;
-    let dpr_scale = render_state.viewbox.zoom * render_state.options.dpr();
 
     // Save canvas and layer state
     let mut pb = skia::Paint::default();
@@ -352,7 +504,7 @@ fn draw_image_stroke_in_container(
     // Draw the stroke based on the kind, we are using this stroke as a "selector" of the area of the image we want to show.
     let outer_rect = stroke.outer_rect(container);
     match kind {
-        Kind::Rect(rect, corners) => draw_stroke_on_rect(
+        Kind::Rect(rect, corners) | Kind::Frame(rect, corners) => draw_stroke_on_rect(
             canvas,
             stroke,
             rect,
@@ -364,11 +516,12 @@ fn draw_image_stroke_in_container(
         Kind::Circle(rect) => {
             draw_stroke_on_circle(canvas, stroke, rect, &outer_rect, svg_attrs, dpr_scale)
         }
-        Kind::SVGRaw(_) => todo!(),
+        // These kinds paint their own content
+        Kind::SVGRaw(_) | Kind::Text(_) | Kind::Group(_) => {}
         Kind::Path(p) | Kind::Bool(_, p) => {
             canvas.save();
             let mut path = p.to_skia_path();
-            path.transform(&path_transform.unwrap());
+            path.transform(&path_transform);
             let stroke_kind = stroke.render_kind(p.is_open());
             match stroke_kind {
                 StrokeKind::InnerStroke => {
@@ -381,7 +534,7 @@ fn draw_image_stroke_in_container(
             }
             let is_open = p.is_open();
             let mut paint = stroke.to_stroked_paint(is_open, &outer_rect, svg_attrs, dpr_scale);
-            canvas.draw_path(&path, &paint);
+            draw_stroke_path(canvas, stroke, &path, &paint, svg_attrs);
             canvas.restore();
             if stroke.render_kind(is_open) == StrokeKind::OuterStroke {
                 // Small extra inner stroke to overlap with the fill and avoid unnecesary artifacts
@@ -404,16 +557,16 @@ fn draw_image_stroke_in_container(
     image_paint.set_blend_mode(skia::BlendMode::SrcIn);
     image_paint.set_anti_alias(true);
 
-    // Compute scaled rect and clip to it
-    let dest_rect = calculate_scaled_rect(size, container, stroke.delta());
-    canvas.clip_rect(dest_rect, skia::ClipOp::Intersect, true);
-    canvas.draw_image_rect(image.unwrap(), None, dest_rect, &image_paint);
+    // The image covers the whole stroke area, including the outer part of it
+    let delta = stroke.delta();
+    let image_container = container.with_outset((delta, delta));
+    images::draw_image_fill(canvas, &image, image_fill, &image_container, &image_paint);
 
     // Clear outer stroke for paths if necessary. When adding an outer stroke we need to empty the stroke added too in the inner area.
     if let Kind::Path(p) = kind {
         if stroke.render_kind(p.is_open()) == StrokeKind::OuterStroke {
             let mut path = p.to_skia_path();
-            path.transform(&path_transform.unwrap());
+            path.transform(&path_transform);
             let mut clear_paint = skia::Paint::default();
             clear_paint.set_blend_mode(skia::BlendMode::Clear);
             clear_paint.set_anti_alias(true);
@@ -429,8 +582,8 @@ fn draw_image_stroke_in_container(
  * This SHOULD be the only public function in this module.
  */
 pub fn render(render_state: &mut RenderState, shape: &Shape, stroke: &Stroke) {
-    let canvas = render_state.drawing_surface.canvas();
     let dpr_scale = render_state.viewbox.zoom * render_state.options.dpr();
+    let canvas = render_state.drawing_canvas();
     let selrect = shape.selrect;
     let path_transform = shape.to_path_transform();
     let kind = &shape.kind;
@@ -439,7 +592,7 @@ pub fn render(render_state: &mut RenderState, shape: &Shape, stroke: &Stroke) {
         draw_image_stroke_in_container(render_state, shape, stroke, image_fill);
     } else {
         match kind {
-            Kind::Rect(rect, corners) => draw_stroke_on_rect(
+            Kind::Rect(rect, corners) | Kind::Frame(rect, corners) => draw_stroke_on_rect(
                 canvas, stroke, rect, &selrect, corners, svg_attrs, dpr_scale,
             ),
             Kind::Circle(rect) => {
@@ -457,7 +610,8 @@ pub fn render(render_state: &mut RenderState, shape: &Shape, stroke: &Stroke) {
                     dpr_scale,
                 );
             }
-            Kind::SVGRaw(_) => todo!(),
+            // These kinds paint their own content
+            Kind::SVGRaw(_) | Kind::Text(_) | Kind::Group(_) => {}
         }
     }
 }
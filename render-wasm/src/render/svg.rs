@@ -0,0 +1,30 @@
+use skia_safe as skia;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use super::RenderState;
+use crate::shapes::{subtree_visual_bounds, Shape};
+
+/// Renders the subtree rooted at the given shape through the SVG canvas, sized to its
+/// visual bounds. Texts are drawn as glyph paths when `text_to_paths` is set.
+pub fn export_svg(
+    render_state: &mut RenderState,
+    id: &Uuid,
+    tree: &HashMap<Uuid, Shape>,
+    text_to_paths: bool,
+) -> Result<String, String> {
+    let area = subtree_visual_bounds(id, tree).ok_or("Shape not found or hidden")?;
+    let picture = render_state.record_shape_tree(id, tree, area)?;
+
+    let flags = if text_to_paths {
+        skia::svg::canvas::Flags::CONVERT_TEXT_TO_PATHS
+    } else {
+        skia::svg::canvas::Flags::empty()
+    };
+    let canvas = skia::svg::Canvas::new(skia::Rect::from_wh(area.width(), area.height()), flags);
+    canvas.translate((-area.left, -area.top));
+    canvas.draw_picture(&picture, None, None);
+    let data = canvas.end();
+
+    String::from_utf8(data.as_bytes().to_vec()).map_err(|e| e.to_string())
+}
@@ -0,0 +1,14 @@
+use super::RenderState;
+use crate::shapes::{Shape, TextContent};
+
+pub fn render(render_state: &mut RenderState, shape: &Shape, content: &TextContent) {
+    let container = &shape.selrect;
+    let mut offset_y = container.top;
+
+    for paragraph in content.paragraphs() {
+        let mut skia_paragraph = paragraph.to_skia_paragraph(&render_state.font_collection);
+        skia_paragraph.layout(container.width());
+        skia_paragraph.paint(render_state.drawing_canvas(), (container.left, offset_y));
+        offset_y += skia_paragraph.height();
+    }
+}
@@ -0,0 +1,239 @@
+use skia::RoundOut;
+use skia_safe as skia;
+use std::collections::{HashMap, VecDeque};
+
+use super::Image;
+use crate::math;
+
+/// Size of the tiles in device pixels.
+pub const TILE_SIZE: i32 = 512;
+
+/// A tile of the canvas, in world space, for a given scale (zoom times dpr).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Tile {
+    scale_key: u32,
+    pub x: i32,
+    pub y: i32,
+}
+
+impl Tile {
+    pub fn new(scale: f32, x: i32, y: i32) -> Self {
+        Self {
+            scale_key: scale.to_bits(),
+            x,
+            y,
+        }
+    }
+
+    pub fn scale(&self) -> f32 {
+        f32::from_bits(self.scale_key)
+    }
+
+    /// Returns the area covered by this tile in world coordinates.
+    pub fn world_rect(&self) -> math::Rect {
+        let size = world_tile_size(self.scale());
+        math::Rect::from_xywh(self.x as f32 * size, self.y as f32 * size, size, size)
+    }
+}
+
+pub fn world_tile_size(scale: f32) -> f32 {
+    TILE_SIZE as f32 / scale
+}
+
+/// Returns the range of tiles, as `(left, top, right, bottom)` (all inclusive), that cover
+/// the given world area.
+pub fn tile_range(area: &math::Rect, scale: f32) -> (i32, i32, i32, i32) {
+    let size = world_tile_size(scale);
+    (
+        (area.left / size).floor() as i32,
+        (area.top / size).floor() as i32,
+        (area.right / size).floor() as i32,
+        (area.bottom / size).floor() as i32,
+    )
+}
+
+/// Rendered tiles, evicting the least recently used ones when it is full.
+pub struct TileCache {
+    images: HashMap<Tile, Image>,
+    // Least recently used tiles first
+    lru: VecDeque<Tile>,
+    capacity: usize,
+    last_scale: Option<f32>,
+}
+
+impl TileCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            images: HashMap::with_capacity(capacity),
+            lru: VecDeque::with_capacity(capacity),
+            capacity,
+            last_scale: None,
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn contains(&self, tile: &Tile) -> bool {
+        self.images.contains_key(tile)
+    }
+
+    pub fn get(&mut self, tile: &Tile) -> Option<&Image> {
+        if self.images.contains_key(tile) {
+            self.touch(tile);
+        }
+        self.images.get(tile)
+    }
+
+    pub fn insert(&mut self, tile: Tile, image: Image) {
+        self.images.insert(tile, image);
+        self.touch(&tile);
+        self.last_scale = Some(tile.scale());
+        self.evict();
+    }
+
+    /// Returns the cached tiles rendered at the given scale.
+    pub fn tiles_at(&self, scale: f32) -> impl Iterator<Item = (&Tile, &Image)> {
+        let scale_key = scale.to_bits();
+        self.images
+            .iter()
+            .filter(move |(tile, _)| tile.scale_key == scale_key)
+    }
+
+    /// Scale of the last rendered tile.
+    pub fn last_scale(&self) -> Option<f32> {
+        self.last_scale
+    }
+
+    /// Removes the tiles of any scale that overlap the given world area, and returns them.
+    pub fn take_intersecting(&mut self, area: &math::Rect) -> HashMap<Tile, Image> {
+        let tiles: Vec<Tile> = self
+            .images
+            .keys()
+            .filter(|tile| tile.world_rect().intersects(area))
+            .copied()
+            .collect();
+        self.lru.retain(|tile| !tiles.contains(tile));
+        tiles
+            .into_iter()
+            .filter_map(|tile| Some((tile, self.images.remove(&tile)?)))
+            .collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.images.clear();
+        self.lru.clear();
+    }
+
+    fn touch(&mut self, tile: &Tile) {
+        if let Some(index) = self.lru.iter().position(|t| t == tile) {
+            self.lru.remove(index);
+        }
+        self.lru.push_back(*tile);
+    }
+
+    fn evict(&mut self) {
+        while self.images.len() > self.capacity {
+            match self.lru.pop_front() {
+                Some(tile) => {
+                    self.images.remove(&tile);
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Tiles painted around the missing ones when they have background blurs, which read what
+/// lies beneath them up to this many tiles away.
+pub const TILE_MARGIN: i32 = 1;
+
+/// Size in device pixels of the surfaces where the tiles are rendered, one at a time: a tile
+/// plus the margin around it.
+pub const SURFACE_SIZE: i32 = TILE_SIZE * (1 + 2 * TILE_MARGIN);
+
+/// Returns the tile grid size (columns, rows) needed to cover a viewport of the given size
+/// in device pixels, wherever it is placed.
+pub fn grid_size(width: i32, height: i32) -> (i32, i32) {
+    let tiles = |size: i32| (size as f32 / TILE_SIZE as f32).ceil() as i32 + 1;
+    (tiles(width), tiles(height))
+}
+
+pub fn device_rect(tile: &Tile, origin: (i32, i32)) -> skia::IRect {
+    skia::IRect::from_xywh(
+        (tile.x - origin.0) * TILE_SIZE,
+        (tile.y - origin.1) * TILE_SIZE,
+        TILE_SIZE,
+        TILE_SIZE,
+    )
+}
+
+/// Returns the device pixels covering the given world area, relative to the `origin` tile.
+pub fn device_area(area: &math::Rect, scale: f32, origin: (i32, i32)) -> skia::IRect {
+    let size = world_tile_size(scale);
+    let (x, y) = (origin.0 as f32 * size, origin.1 as f32 * size);
+    math::Rect::from_ltrb(
+        (area.left - x) * scale,
+        (area.top - y) * scale,
+        (area.right - x) * scale,
+        (area.bottom - y) * scale,
+    )
+    .round_out()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn any_image() -> Image {
+        let mut surface = skia::surfaces::raster_n32_premul((1, 1)).unwrap();
+        surface.image_snapshot()
+    }
+
+    #[test]
+    fn least_recently_used_tiles_are_evicted_first() {
+        let mut cache = TileCache::new(2);
+        let (a, b, c) = (
+            Tile::new(1., 0, 0),
+            Tile::new(1., 1, 0),
+            Tile::new(1., 2, 0),
+        );
+
+        cache.insert(a, any_image());
+        cache.insert(b, any_image());
+        cache.get(&a);
+        cache.insert(c, any_image());
+
+        assert!(cache.contains(&a));
+        assert!(!cache.contains(&b));
+        assert!(cache.contains(&c));
+    }
+
+    #[test]
+    fn taking_an_area_removes_the_tiles_of_every_scale_over_it() {
+        let mut cache = TileCache::new(4);
+        let (a, b, c) = (
+            Tile::new(1., 0, 0),
+            Tile::new(1., 1, 0),
+            Tile::new(2., 0, 0),
+        );
+        cache.insert(a, any_image());
+        cache.insert(b, any_image());
+        cache.insert(c, any_image());
+
+        let taken = cache.take_intersecting(&math::Rect::from_xywh(10., 10., 20., 20.));
+
+        assert!(taken.contains_key(&a) && taken.contains_key(&c));
+        assert!(!cache.contains(&a) && !cache.contains(&c));
+        assert!(cache.contains(&b));
+    }
+
+    #[test]
+    fn tile_range_covers_the_area_at_the_given_scale() {
+        let area = math::Rect::from_xywh(-10., 0., 1024., 100.);
+        assert_eq!(tile_range(&area, 1.), (-1, 0, 1, 0));
+        assert_eq!(tile_range(&area, 2.), (-1, 0, 3, 0));
+    }
+}
@@ -8,20 +8,26 @@ use crate::render::BlendMode;
 mod blurs;
 mod bools;
 mod fills;
+mod groups;
 mod matrix;
 mod paths;
+mod records;
 mod shadows;
 mod strokes;
 mod svgraw;
+mod text;
 
 pub use blurs::*;
 pub use bools::*;
 pub use fills::*;
+pub use groups::*;
 use matrix::*;
 pub use paths::*;
+pub use records::*;
 pub use shadows::*;
 pub use strokes::*;
 pub use svgraw::*;
+pub use text::*;
 
 pub type CornerRadius = skia::Point;
 pub type Corners = [CornerRadius; 4];
@@ -33,6 +39,11 @@ pub enum Kind {
     Path(Path),
     Bool(BoolType, Path),
     SVGRaw(SVGRaw),
+    Text(TextContent),
+    // Boards: they draw their strokes above their children, which they can clip to their outline
+    Frame(math::Rect, Option<Corners>),
+    // Only paints its children
+    Group(Group),
 }
 
 pub type Color = skia::Color;
@@ -53,7 +64,6 @@ pub struct Shape {
     pub blur: Blur,
     pub opacity: f32,
     pub hidden: bool,
-    pub svg: Option<skia::svg::Dom>,
     pub svg_attrs: HashMap<String, String>,
     shadows: Vec<Shadow>,
 }
@@ -74,7 +84,6 @@ impl Shape {
             opacity: 1.,
             hidden: false,
             blur: Blur::default(),
-            svg: None,
             svg_attrs: HashMap::new(),
             shadows: vec![],
         }
@@ -90,6 +99,9 @@ impl Shape {
             Kind::Rect(_, corners) => {
                 self.kind = Kind::Rect(self.selrect.to_owned(), corners);
             }
+            Kind::Frame(_, corners) => {
+                self.kind = Kind::Frame(self.selrect.to_owned(), corners);
+            }
             Kind::Circle(_) => {
                 self.kind = Kind::Circle(self.selrect.to_owned());
             }
@@ -150,6 +162,8 @@ impl Shape {
         let gradient = match fill {
             Fill::LinearGradient(g) => Ok(g),
             Fill::RadialGradient(g) => Ok(g),
+            Fill::AngularGradient(g) => Ok(g),
+            Fill::DiamondGradient(g) => Ok(g),
             _ => Err("Active fill is not a gradient"),
         }?;
 
@@ -174,12 +188,20 @@ impl Shape {
         Ok(())
     }
 
+    pub fn set_stroke_dash(&mut self, dash: Option<Dash>) -> Result<(), String> {
+        let stroke = self.strokes.last_mut().ok_or("Shape has no strokes")?;
+        stroke.dash = dash;
+        Ok(())
+    }
+
     pub fn add_stroke_gradient_stops(&mut self, buffer: Vec<RawStopData>) -> Result<(), String> {
         let stroke = self.strokes.last_mut().ok_or("Shape has no strokes")?;
         let fill = &mut stroke.fill;
         let gradient = match fill {
             Fill::LinearGradient(g) => Ok(g),
             Fill::RadialGradient(g) => Ok(g),
+            Fill::AngularGradient(g) => Ok(g),
+            Fill::DiamondGradient(g) => Ok(g),
             _ => Err("Active stroke is not a gradient"),
         }?;
 
@@ -190,6 +212,27 @@ impl Shape {
         Ok(())
     }
 
+    pub fn set_image_fill_crop(&mut self, crop: (f32, f32, f32, f32)) -> Result<(), String> {
+        match self.fills.last_mut() {
+            Some(Fill::Image(image_fill)) => {
+                image_fill.set_crop(crop.0, crop.1, crop.2, crop.3);
+                Ok(())
+            }
+            _ => Err("Active fill is not an image".to_string()),
+        }
+    }
+
+    pub fn set_image_stroke_crop(&mut self, crop: (f32, f32, f32, f32)) -> Result<(), String> {
+        let stroke = self.strokes.last_mut().ok_or("Shape has no strokes")?;
+        match &mut stroke.fill {
+            Fill::Image(image_fill) => {
+                image_fill.set_crop(crop.0, crop.1, crop.2, crop.3);
+                Ok(())
+            }
+            _ => Err("Active stroke is not an image".to_string()),
+        }
+    }
+
     pub fn clear_strokes(&mut self) {
         self.strokes.clear();
     }
@@ -205,13 +248,20 @@ impl Shape {
         Ok(())
     }
 
-    pub fn set_path_attr(&mut self, name: String, value: String) {
+    pub fn set_path_attr(&mut self, name: String, value: String) -> Result<(), String> {
         match &mut self.kind {
             Kind::Path(_) => {
                 self.set_svg_attr(name, value);
+                Ok(())
             }
-            Kind::Rect(_, _) | Kind::Circle(_) | Kind::SVGRaw(_) | Kind::Bool(_, _) => todo!(),
-        };
+            Kind::Rect(_, _)
+            | Kind::Circle(_)
+            | Kind::SVGRaw(_)
+            | Kind::Bool(_, _)
+            | Kind::Text(_)
+            | Kind::Frame(_, _)
+            | Kind::Group(_) => Err("Shape is not a path".to_string()),
+        }
     }
 
     pub fn set_svg_raw_content(&mut self, content: String) -> Result<(), String> {
@@ -219,6 +269,30 @@ impl Shape {
         Ok(())
     }
 
+    pub fn clear_text_content(&mut self) {
+        self.kind = Kind::Text(TextContent::default());
+    }
+
+    pub fn add_text_paragraph_set(&mut self) -> Result<(), String> {
+        self.text_content_mut()?.add_paragraph_set();
+        Ok(())
+    }
+
+    pub fn add_text_paragraph(&mut self, paragraph: Paragraph) -> Result<(), String> {
+        self.text_content_mut()?.add_paragraph(paragraph)
+    }
+
+    pub fn add_text_leaf(&mut self, leaf: TextLeaf) -> Result<(), String> {
+        self.text_content_mut()?.add_leaf(leaf)
+    }
+
+    fn text_content_mut(&mut self) -> Result<&mut TextContent, String> {
+        match &mut self.kind {
+            Kind::Text(content) => Ok(content),
+            _ => Err("Shape is not a text".to_string()),
+        }
+    }
+
     pub fn set_blend_mode(&mut self, mode: BlendMode) {
         self.blend_mode = mode;
     }
@@ -250,11 +324,10 @@ impl Shape {
             ])
         };
 
-        self.kind = Kind::Rect(self.selrect, corners);
-    }
-
-    pub fn set_svg(&mut self, svg: skia::svg::Dom) {
-        self.svg = Some(svg);
+        self.kind = match self.kind {
+            Kind::Frame(_, _) => Kind::Frame(self.selrect, corners),
+            _ => Kind::Rect(self.selrect, corners),
+        };
     }
 
     pub fn set_svg_attr(&mut self, name: String, value: String) {
@@ -277,8 +350,27 @@ impl Shape {
         self.selrect
     }
 
-    pub fn clip(&self) -> bool {
-        self.clip_content
+    pub fn set_masked(&mut self, masked: bool) -> Result<(), String> {
+        match &mut self.kind {
+            Kind::Group(group) => {
+                group.masked = masked;
+                Ok(())
+            }
+            _ => Err("Shape is not a group".to_string()),
+        }
+    }
+
+    /// Returns the child used as the mask of the rest, if the shape is a masked group.
+    pub fn mask_id(&self) -> Option<Uuid> {
+        match self.kind {
+            Kind::Group(Group { masked: true }) => self.children.first().copied(),
+            _ => None,
+        }
+    }
+
+    /// Only frames clip their children, to their rounded and transformed outline.
+    pub fn clips_children(&self) -> bool {
+        matches!(self.kind, Kind::Frame(_, _)) && self.clip_content
     }
 
     pub fn children_ids(&self) -> Vec<Uuid> {
@@ -299,12 +391,26 @@ impl Shape {
                     None,
                     None,
                 ),
+                BlurType::Background => None,
             }
         } else {
             None
         }
     }
 
+    pub fn backdrop_filter(&self, scale: f32) -> Option<skia::ImageFilter> {
+        if !self.blur.hidden && self.blur.blur_type == BlurType::Background {
+            skia::image_filters::blur(
+                (self.blur.value * scale, self.blur.value * scale),
+                skia::TileMode::Clamp,
+                None,
+                None,
+            )
+        } else {
+            None
+        }
+    }
+
     pub fn is_recursive(&self) -> bool {
         !matches!(self.kind, Kind::SVGRaw(_))
     }
@@ -313,6 +419,17 @@ impl Shape {
         self.shadows.push(shadow);
     }
 
+    /// Returns the ids of the images used by the fills and strokes of the shape.
+    pub fn image_ids(&self) -> impl Iterator<Item = Uuid> + '_ {
+        self.fills
+            .iter()
+            .chain(self.strokes.iter().map(|stroke| &stroke.fill))
+            .filter_map(|fill| match fill {
+                Fill::Image(image_fill) => Some(image_fill.id()),
+                _ => None,
+            })
+    }
+
     pub fn clear_shadows(&mut self) {
         self.shadows.clear();
     }
@@ -323,6 +440,119 @@ impl Shape {
             .filter(|shadow| shadow.style() == ShadowStyle::Drop)
     }
 
+    pub fn inner_shadows(&self) -> impl DoubleEndedIterator<Item = &Shadow> {
+        self.shadows
+            .iter()
+            .filter(|shadow| shadow.style() == ShadowStyle::Inner)
+    }
+
+    /// Returns the area painted by the shape in world coordinates, including its transform,
+    /// strokes and their caps, drop shadows and layer blur. Children are not included (see
+    /// [subtree_visual_bounds]).
+    pub fn visual_bounds(&self) -> math::Rect {
+        let (mut rect, _) = self.centered_transform().map_rect(self.selrect);
+
+        let is_open = self.is_open();
+        let stroke_outset = self
+            .strokes
+            .iter()
+            .map(|stroke| stroke.outset(is_open))
+            .fold(0., f32::max);
+        rect.outset((stroke_outset, stroke_outset));
+
+        let mut bounds = rect;
+        for shadow in self.drop_shadows().filter(|s| !s.hidden()) {
+            bounds.join(shadow.visual_bounds(&rect));
+        }
+
+        if !self.blur.hidden && self.blur.blur_type == BlurType::Layer {
+            bounds.outset((self.blur.value * 3., self.blur.value * 3.));
+        }
+
+        bounds
+    }
+
+    pub fn is_open(&self) -> bool {
+        matches!(&self.kind, Kind::Path(path) if path.is_open())
+    }
+
+    // Check transform-matrix code from common/src/app/common/geom/shapes/transforms.cljc
+    pub fn centered_transform(&self) -> skia::Matrix {
+        let center = self.bounds().center();
+        let mut matrix = skia::Matrix::new_identity();
+        matrix.pre_translate(center);
+        matrix.pre_concat(&self.transform.to_skia_matrix());
+        matrix.pre_translate(-center);
+        matrix
+    }
+
+    /// Returns the geometry of the shape before applying its transform.
+    pub fn to_skia_path(&self) -> Option<skia::Path> {
+        match &self.kind {
+            Kind::Rect(rect, None) | Kind::Frame(rect, None) => Some(skia::Path::rect(rect, None)),
+            Kind::Rect(rect, Some(corners)) | Kind::Frame(rect, Some(corners)) => Some(
+                skia::Path::rrect(skia::RRect::new_rect_radii(rect, corners), None),
+            ),
+            Kind::Circle(rect) => Some(skia::Path::oval(rect, None)),
+            Kind::Path(path) | Kind::Bool(_, path) => {
+                let mut skia_path = path.to_skia_path();
+                skia_path.transform(&self.to_path_transform()?);
+                if let Some("evenodd") = self.svg_attrs.get("fill-rule").map(String::as_str) {
+                    skia_path.set_fill_type(skia::PathFillType::EvenOdd);
+                }
+                Some(skia_path)
+            }
+            Kind::SVGRaw(_) | Kind::Text(_) | Kind::Group(_) => None,
+        }
+    }
+
+    /// Returns the geometry of the shape in world coordinates.
+    pub fn to_world_path(&self) -> Option<skia::Path> {
+        Some(
+            self.to_skia_path()?
+                .with_transform(&self.centered_transform()),
+        )
+    }
+
+    /// Returns the area where the shape can be hit, in world coordinates: its geometry and
+    /// the part of its strokes out of it. Open paths can only be hit on their strokes.
+    pub fn hit_path(&self) -> skia::Path {
+        let path = self.to_world_path().unwrap_or_else(|| {
+            skia::Path::rect(self.selrect, None).with_transform(&self.centered_transform())
+        });
+
+        let is_open = self.is_open();
+        let stroke_width = if is_open {
+            // Unstroked open paths can still be hit on their line
+            self.strokes
+                .iter()
+                .map(|stroke| stroke.width)
+                .fold(1., f32::max)
+        } else {
+            self.strokes
+                .iter()
+                .map(|stroke| stroke.delta() * 2.)
+                .fold(0., f32::max)
+        };
+        if stroke_width <= 0. {
+            return path;
+        }
+
+        let mut paint = skia::Paint::default();
+        paint.set_style(skia::PaintStyle::Stroke);
+        paint.set_stroke_width(stroke_width);
+        let mut outline = skia::Path::new();
+        if !skia::path_utils::fill_path_with_paint(&path, &paint, &mut outline, None, None) {
+            return path;
+        }
+
+        if is_open {
+            outline
+        } else {
+            path.op(&outline, skia::PathOp::Union).unwrap_or(path)
+        }
+    }
+
     pub fn to_path_transform(&self) -> Option<skia::Matrix> {
         match self.kind {
             Kind::Path(_) | Kind::Bool(_, _) => {
@@ -339,6 +569,55 @@ impl Shape {
     }
 }
 
+/// Returns the area painted by a shape and all its visible descendants.
+pub fn subtree_visual_bounds(id: &Uuid, tree: &HashMap<Uuid, Shape>) -> Option<math::Rect> {
+    let shape = tree.get(id)?;
+    if shape.hidden() {
+        return None;
+    }
+
+    let mut bounds = shape.visual_bounds();
+    if shape.is_recursive() {
+        if let Some(children_bounds) = children_visual_bounds(shape, tree) {
+            bounds.join(children_bounds);
+        }
+    }
+
+    Some(bounds)
+}
+
+// Returns the area painted by the children of a shape, which clipping frames limit to their
+// outline and masked groups to the area of their mask.
+fn children_visual_bounds(shape: &Shape, tree: &HashMap<Uuid, Shape>) -> Option<math::Rect> {
+    let mask_id = shape.mask_id();
+    let mut bounds: Option<math::Rect> = None;
+    for child_id in shape.children_ids() {
+        // The mask is not painted itself
+        if Some(child_id) == mask_id {
+            continue;
+        }
+        if let Some(child_bounds) = subtree_visual_bounds(&child_id, tree) {
+            match bounds.as_mut() {
+                Some(bounds) => bounds.join(child_bounds),
+                None => bounds = Some(child_bounds),
+            }
+        }
+    }
+
+    if shape.clips_children() {
+        let outline = match shape.to_world_path() {
+            Some(path) => *path.bounds(),
+            None => shape.centered_transform().map_rect(shape.selrect).0,
+        };
+        bounds = bounds.and_then(|bounds| math::Rect::intersect(&bounds, &outline));
+    }
+    if let Some(mask_id) = mask_id {
+        let mask_bounds = subtree_visual_bounds(&mask_id, tree)?;
+        bounds = bounds.and_then(|bounds| math::Rect::intersect(&bounds, &mask_bounds));
+    }
+    bounds
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -355,4 +634,116 @@ mod tests {
         shape.add_fill(Fill::Solid(Color::TRANSPARENT));
         assert_eq!(shape.fills.get(0), Some(&Fill::Solid(Color::TRANSPARENT)))
     }
+
+    #[test]
+    fn shadows_are_split_by_style() {
+        let mut shape = any_shape();
+        let drop = Shadow::new(Color::BLACK, 4., 0., (2., 2.), ShadowStyle::Drop, false);
+        let inner = Shadow::new(Color::BLACK, 4., 1., (0., 2.), ShadowStyle::Inner, false);
+        shape.add_shadow(drop);
+        shape.add_shadow(inner);
+
+        assert_eq!(shape.drop_shadows().collect::<Vec<_>>(), vec![&drop]);
+        assert_eq!(shape.inner_shadows().collect::<Vec<_>>(), vec![&inner]);
+    }
+
+    #[test]
+    fn visual_bounds_include_transform_strokes_and_shadows() {
+        let mut shape = any_shape();
+        shape.set_selrect(0., 0., 100., 50.);
+        // Rotated 90 degrees around its center
+        shape.set_transform(0., 1., -1., 0., 0., 0.);
+        shape.add_stroke(Stroke::new_outer_stroke(10., 0, 0, 0));
+        shape.add_shadow(Shadow::new(
+            Color::BLACK,
+            0.,
+            5.,
+            (20., 0.),
+            ShadowStyle::Drop,
+            false,
+        ));
+
+        let bounds = shape.visual_bounds();
+        assert_eq!(bounds, math::Rect::from_ltrb(15., -40., 110., 90.));
+    }
+
+    #[test]
+    fn visual_bounds_of_open_paths_include_their_caps() {
+        let mut shape = any_shape();
+        shape.set_selrect(0., 0., 100., 0.);
+        let mut path = skia::Path::new();
+        path.move_to((0., 0.)).line_to((100., 0.));
+        shape.set_kind(Kind::Path(Path::from(path)));
+        shape.add_stroke(Stroke::new_center_stroke(2., 0, 4, 0));
+
+        let bounds = shape.visual_bounds();
+        assert_eq!(bounds, math::Rect::from_ltrb(-4., -4., 104., 4.));
+    }
+
+    #[test]
+    fn subtree_visual_bounds_join_the_visible_children() {
+        let (child_id, hidden_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut parent = any_shape();
+        parent.set_selrect(0., 0., 10., 10.);
+        parent.add_child(child_id);
+        parent.add_child(hidden_id);
+        let mut child = Shape::new(child_id);
+        child.set_selrect(50., 50., 60., 60.);
+        let mut hidden = Shape::new(hidden_id);
+        hidden.set_selrect(100., 100., 200., 200.);
+        hidden.set_hidden(true);
+        let tree = HashMap::from([
+            (Uuid::nil(), parent),
+            (child_id, child),
+            (hidden_id, hidden),
+        ]);
+
+        assert_eq!(
+            subtree_visual_bounds(&Uuid::nil(), &tree),
+            Some(math::Rect::from_ltrb(0., 0., 60., 60.))
+        );
+    }
+
+    #[test]
+    fn subtree_visual_bounds_of_clipping_frames_stop_at_their_outline() {
+        let child_id = Uuid::new_v4();
+        let mut frame = any_shape();
+        frame.set_kind(Kind::Frame(math::Rect::new_empty(), None));
+        frame.set_selrect(0., 0., 100., 100.);
+        frame.set_clip(true);
+        frame.add_child(child_id);
+        let mut child = Shape::new(child_id);
+        child.set_selrect(50., 50., 300., 300.);
+        let mut tree = HashMap::from([(Uuid::nil(), frame), (child_id, child)]);
+
+        assert_eq!(
+            subtree_visual_bounds(&Uuid::nil(), &tree),
+            Some(math::Rect::from_ltrb(0., 0., 100., 100.))
+        );
+
+        tree.get_mut(&Uuid::nil()).unwrap().set_clip(false);
+        assert_eq!(
+            subtree_visual_bounds(&Uuid::nil(), &tree),
+            Some(math::Rect::from_ltrb(0., 0., 300., 300.))
+        );
+    }
+
+    #[test]
+    fn subtree_visual_bounds_of_masked_groups_stop_at_their_mask() {
+        let (mask_id, content_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut group = any_shape();
+        group.set_kind(Kind::Group(Group::new(true)));
+        group.add_child(mask_id);
+        group.add_child(content_id);
+        let mut mask = Shape::new(mask_id);
+        mask.set_selrect(20., 20., 60., 60.);
+        let mut content = Shape::new(content_id);
+        content.set_selrect(0., 0., 100., 40.);
+        let tree = HashMap::from([(Uuid::nil(), group), (mask_id, mask), (content_id, content)]);
+
+        assert_eq!(
+            subtree_visual_bounds(&Uuid::nil(), &tree),
+            Some(math::Rect::from_ltrb(20., 20., 60., 40.))
+        );
+    }
 }
@@ -2,6 +2,7 @@
 pub enum BlurType {
     None,
     Layer,
+    Background,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -15,6 +16,7 @@ impl From<u8> for BlurType {
     fn from(value: u8) -> Self {
         match value {
             1 => BlurType::Layer,
+            2 => BlurType::Background,
             _ => BlurType::None,
         }
     }
@@ -1,3 +1,9 @@
+use skia_safe as skia;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use super::{Kind, Shape};
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BoolType {
     Union,
@@ -23,3 +29,75 @@ impl Default for BoolType {
         Self::Union
     }
 }
+
+impl From<BoolType> for skia::PathOp {
+    fn from(value: BoolType) -> Self {
+        match value {
+            BoolType::Union => skia::PathOp::Union,
+            BoolType::Difference => skia::PathOp::Difference,
+            BoolType::Intersection => skia::PathOp::Intersect,
+            BoolType::Exclusion => skia::PathOp::XOR,
+        }
+    }
+}
+
+/// Computes the path of a bool shape out of its children. Like in
+/// common/src/app/common/geom/shapes/bool.cljc, the operation is applied to
+/// each pair of visible children in order, and the result is combined with
+/// the next one. The resulting path is in world coordinates.
+pub fn compute_bool_path(shape: &Shape, tree: &HashMap<Uuid, Shape>) -> Option<skia::Path> {
+    let bool_type = match shape.kind {
+        Kind::Bool(bool_type, _) => bool_type,
+        _ => return None,
+    };
+
+    let mut result: Option<skia::Path> = None;
+    for child in shape.children.iter().filter_map(|id| tree.get(id)) {
+        if child.hidden() || child.id == shape.id {
+            continue;
+        }
+
+        let child_path = match child.kind {
+            Kind::Bool(_, _) => compute_bool_path(child, tree),
+            _ => child.to_world_path(),
+        };
+
+        if let Some(child_path) = child_path {
+            result = match result {
+                None => Some(child_path),
+                Some(path) => path.op(&child_path, bool_type.into()).or(Some(path)),
+            };
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect_shape(id: Uuid, left: f32, top: f32, right: f32, bottom: f32) -> Shape {
+        let mut shape = Shape::new(id);
+        shape.set_selrect(left, top, right, bottom);
+        shape
+    }
+
+    #[test]
+    fn difference_removes_the_next_children_from_the_first_one() {
+        let (bool_id, a, b) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut bool_shape = Shape::new(bool_id);
+        bool_shape.set_bool_type(BoolType::Difference);
+        bool_shape.add_child(a);
+        bool_shape.add_child(b);
+
+        let mut tree = HashMap::new();
+        tree.insert(a, rect_shape(a, 0., 0., 100., 100.));
+        tree.insert(b, rect_shape(b, 50., 0., 150., 100.));
+
+        let path = compute_bool_path(&bool_shape, &tree).unwrap();
+        assert_eq!(path.bounds(), &skia::Rect::from_ltrb(0., 0., 50., 100.));
+        assert!(path.contains((25., 50.)));
+        assert!(!path.contains((75., 50.)));
+    }
+}
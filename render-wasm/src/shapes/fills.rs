@@ -11,7 +11,36 @@ pub struct RawStopData {
     offset: u8,
 }
 
+impl From<[u8; 5]> for RawStopData {
+    fn from(value: [u8; 5]) -> Self {
+        Self {
+            color: [value[0], value[1], value[2], value[3]],
+            offset: value[4],
+        }
+    }
+}
+
 impl RawStopData {
+    /// Splits a buffer into its stops, failing if it is not made of whole stops.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Self>, String> {
+        let stop_size = std::mem::size_of::<Self>();
+        if bytes.len() % stop_size != 0 {
+            return Err(format!(
+                "Error deserializing gradient stops. The size of the buffer ({}) is not a multiple of {}",
+                bytes.len(),
+                stop_size
+            ));
+        }
+
+        Ok(bytes
+            .chunks_exact(stop_size)
+            .map(|data| Self {
+                color: [data[0], data[1], data[2], data[3]],
+                offset: data[4],
+            })
+            .collect())
+    }
+
     pub fn color(&self) -> skia::Color {
         skia::Color::from_argb(self.color[3], self.color[0], self.color[1], self.color[2])
     }
@@ -37,7 +66,25 @@ impl Gradient {
         self.offsets.push(offset);
     }
 
-    fn to_linear_shader(&self, rect: &math::Rect) -> skia::Shader {
+    // Gradients without stops can not be painted, so they are left transparent
+    fn to_paint(&self, shader: Option<skia::Shader>) -> skia::Paint {
+        let mut p = skia::Paint::default();
+        match shader {
+            Some(shader) => {
+                p.set_shader(shader);
+                p.set_alpha((self.opacity * 255.) as u8);
+            }
+            None => {
+                p.set_color(skia::Color::TRANSPARENT);
+            }
+        }
+        p.set_style(skia::PaintStyle::Fill);
+        p.set_anti_alias(true);
+        p.set_blend_mode(skia::BlendMode::SrcOver);
+        p
+    }
+
+    fn to_linear_shader(&self, rect: &math::Rect) -> Option<skia::Shader> {
         let start = (
             rect.left + self.start.0 * rect.width(),
             rect.top + self.start.1 * rect.height(),
@@ -46,7 +93,7 @@ impl Gradient {
             rect.left + self.end.0 * rect.width(),
             rect.top + self.end.1 * rect.height(),
         );
-        let shader = skia::shader::Shader::linear_gradient(
+        skia::shader::Shader::linear_gradient(
             (start, end),
             self.colors.as_slice(),
             self.offsets.as_slice(),
@@ -54,11 +101,9 @@ impl Gradient {
             None,
             None,
         )
-        .unwrap();
-        shader
     }
 
-    fn to_radial_shader(&self, rect: &math::Rect) -> skia::Shader {
+    fn to_radial_shader(&self, rect: &math::Rect) -> Option<skia::Shader> {
         let center = skia::Point::new(
             rect.left + self.start.0 * rect.width(),
             rect.top + self.start.1 * rect.height(),
@@ -80,7 +125,7 @@ impl Gradient {
         transform.pre_scale((self.width * rect.width() / rect.height(), 1.), None);
         transform.pre_translate((-center.x, -center.y));
 
-        let shader = skia::shader::Shader::radial_gradient(
+        skia::shader::Shader::radial_gradient(
             center,
             distance,
             self.colors.as_slice(),
@@ -89,8 +134,109 @@ impl Gradient {
             None,
             Some(&transform),
         )
-        .unwrap();
-        shader
+    }
+
+    fn to_angular_shader(&self, rect: &math::Rect) -> Option<skia::Shader> {
+        let center = skia::Point::new(
+            rect.left + self.start.0 * rect.width(),
+            rect.top + self.start.1 * rect.height(),
+        );
+        let end = skia::Point::new(
+            rect.left + self.end.0 * rect.width(),
+            rect.top + self.end.1 * rect.height(),
+        );
+
+        // Skia sweeps start at 3 o'clock, so we rotate them to start at the end handle
+        let direction = end - center;
+        let angle = direction.y.atan2(direction.x).to_degrees();
+        let transform = skia::Matrix::rotate_deg_pivot(angle, center);
+
+        skia::shader::Shader::sweep_gradient(
+            center,
+            self.colors.as_slice(),
+            self.offsets.as_slice(),
+            skia::TileMode::Clamp,
+            None,
+            None,
+            Some(&transform),
+        )
+    }
+
+    fn to_diamond_shader(&self, rect: &math::Rect) -> Option<skia::Shader> {
+        let center = skia::Point::new(
+            rect.left + self.start.0 * rect.width(),
+            rect.top + self.start.1 * rect.height(),
+        );
+        let end = skia::Point::new(
+            rect.left + self.end.0 * rect.width(),
+            rect.top + self.end.1 * rect.height(),
+        );
+
+        let direction = end - center;
+        let distance = (direction.x.powi(2) + direction.y.powi(2)).sqrt();
+        let angle = direction.y.atan2(direction.x).to_degrees();
+
+        // Same transform as the radial gradients, but mapping a diamond of radius 1 to the
+        // distance between the handles
+        let mut transform = skia::Matrix::new_identity();
+        transform.pre_translate((center.x, center.y));
+        transform.pre_rotate(angle + 90., skia::Point::new(0., 0.));
+        transform.pre_scale((self.width * rect.width() / rect.height(), 1.), None);
+        transform.pre_scale((distance, distance), None);
+
+        // The color ramp goes from (0, 0) to (1, 0) and the runtime effect samples it with
+        // the diamond distance to the center
+        let ramp = skia::shader::Shader::linear_gradient(
+            ((0., 0.), (1., 0.)),
+            self.colors.as_slice(),
+            self.offsets.as_slice(),
+            skia::TileMode::Clamp,
+            None,
+            None,
+        )?;
+
+        DIAMOND_GRADIENT_EFFECT.with(|effect| {
+            effect.as_ref().ok()?.make_shader(
+                skia::Data::new_empty(),
+                &[ramp.into()],
+                Some(&transform),
+            )
+        })
+    }
+}
+
+const DIAMOND_GRADIENT_SKSL: &str = r#"
+uniform shader ramp;
+
+half4 main(float2 p) {
+    float t = abs(p.x) + abs(p.y);
+    return ramp.eval(float2(t, 0.5));
+}
+"#;
+
+thread_local! {
+    static DIAMOND_GRADIENT_EFFECT: Result<skia::RuntimeEffect, String> =
+        skia::RuntimeEffect::make_for_shader(DIAMOND_GRADIENT_SKSL, None);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageScaleMode {
+    Fill,
+    Fit,
+    Stretch,
+    Tile,
+    Crop,
+}
+
+impl From<u8> for ImageScaleMode {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Fit,
+            2 => Self::Stretch,
+            3 => Self::Tile,
+            4 => Self::Crop,
+            _ => Self::Fill,
+        }
     }
 }
 
@@ -100,6 +246,10 @@ pub struct ImageFill {
     opacity: u8,
     height: i32,
     width: i32,
+    scale_mode: ImageScaleMode,
+    tile_scale: f32,
+    // Where the image is placed, relative to the container (from 0 to 1)
+    crop: math::Rect,
 }
 
 impl ImageFill {
@@ -110,6 +260,58 @@ impl ImageFill {
     pub fn id(&self) -> Uuid {
         self.id
     }
+
+    pub fn scale_mode(&self) -> ImageScaleMode {
+        self.scale_mode
+    }
+
+    pub fn set_crop(&mut self, x: f32, y: f32, width: f32, height: f32) {
+        self.scale_mode = ImageScaleMode::Crop;
+        self.crop = math::Rect::from_xywh(x, y, width, height);
+    }
+
+    /// Returns the rect where the image has to be drawn for the given container.
+    /// For tiled images, this is the rect of the first tile.
+    pub fn dest_rect(&self, container: &math::Rect) -> math::Rect {
+        let (width, height) = (self.width as f32, self.height as f32);
+        let (container_width, container_height) = (container.width(), container.height());
+
+        match self.scale_mode {
+            ImageScaleMode::Fill | ImageScaleMode::Fit => {
+                let width_ratio = container_width / width;
+                let height_ratio = container_height / height;
+                // To fill the container the image has to cover it, to fit in it
+                // the image has to be contained by it
+                let scale = if self.scale_mode == ImageScaleMode::Fill {
+                    width_ratio.max(height_ratio)
+                } else {
+                    width_ratio.min(height_ratio)
+                };
+
+                let scaled_width = width * scale;
+                let scaled_height = height * scale;
+                math::Rect::from_xywh(
+                    container.left - (scaled_width - container_width) / 2.0,
+                    container.top - (scaled_height - container_height) / 2.0,
+                    scaled_width,
+                    scaled_height,
+                )
+            }
+            ImageScaleMode::Stretch => *container,
+            ImageScaleMode::Tile => math::Rect::from_xywh(
+                container.left,
+                container.top,
+                width * self.tile_scale,
+                height * self.tile_scale,
+            ),
+            ImageScaleMode::Crop => math::Rect::from_xywh(
+                container.left + self.crop.left * container_width,
+                container.top + self.crop.top * container_height,
+                self.crop.width() * container_width,
+                self.crop.height() * container_height,
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -117,6 +319,8 @@ pub enum Fill {
     Solid(Color),
     LinearGradient(Gradient),
     RadialGradient(Gradient),
+    AngularGradient(Gradient),
+    DiamondGradient(Gradient),
     Image(ImageFill),
 }
 
@@ -147,12 +351,57 @@ impl Fill {
         })
     }
 
-    pub fn new_image_fill(id: Uuid, opacity: u8, (width, height): (i32, i32)) -> Self {
+    pub fn new_angular_gradient(start: (f32, f32), end: (f32, f32), opacity: f32) -> Self {
+        Self::AngularGradient(Gradient {
+            start,
+            end,
+            opacity,
+            colors: vec![],
+            offsets: vec![],
+            width: 0.,
+        })
+    }
+
+    /// Fails when the shader of the diamond gradients can not be compiled, as they could not
+    /// be painted.
+    pub fn new_diamond_gradient(
+        start: (f32, f32),
+        end: (f32, f32),
+        opacity: f32,
+        width: f32,
+    ) -> Result<Self, String> {
+        DIAMOND_GRADIENT_EFFECT.with(|effect| match effect {
+            Ok(_) => Ok(()),
+            Err(e) => Err(format!(
+                "Could not compile the diamond gradient shader: {}",
+                e
+            )),
+        })?;
+        Ok(Self::DiamondGradient(Gradient {
+            start,
+            end,
+            opacity,
+            colors: vec![],
+            offsets: vec![],
+            width,
+        }))
+    }
+
+    pub fn new_image_fill(
+        id: Uuid,
+        opacity: u8,
+        (width, height): (i32, i32),
+        scale_mode: ImageScaleMode,
+        tile_scale: f32,
+    ) -> Self {
         Self::Image(ImageFill {
             id,
             opacity,
             height,
             width,
+            scale_mode,
+            tile_scale,
+            crop: math::Rect::from_wh(1., 1.),
         })
     }
 
@@ -166,24 +415,10 @@ impl Fill {
                 p.set_blend_mode(skia::BlendMode::SrcOver);
                 p
             }
-            Self::LinearGradient(gradient) => {
-                let mut p = skia::Paint::default();
-                p.set_shader(gradient.to_linear_shader(&rect));
-                p.set_alpha((gradient.opacity * 255.) as u8);
-                p.set_style(skia::PaintStyle::Fill);
-                p.set_anti_alias(true);
-                p.set_blend_mode(skia::BlendMode::SrcOver);
-                p
-            }
-            Self::RadialGradient(gradient) => {
-                let mut p = skia::Paint::default();
-                p.set_shader(gradient.to_radial_shader(&rect));
-                p.set_alpha((gradient.opacity * 255.) as u8);
-                p.set_style(skia::PaintStyle::Fill);
-                p.set_anti_alias(true);
-                p.set_blend_mode(skia::BlendMode::SrcOver);
-                p
-            }
+            Self::LinearGradient(gradient) => gradient.to_paint(gradient.to_linear_shader(rect)),
+            Self::RadialGradient(gradient) => gradient.to_paint(gradient.to_radial_shader(rect)),
+            Self::AngularGradient(gradient) => gradient.to_paint(gradient.to_angular_shader(rect)),
+            Self::DiamondGradient(gradient) => gradient.to_paint(gradient.to_diamond_shader(rect)),
             Self::Image(image_fill) => {
                 let mut p = skia::Paint::default();
                 p.set_style(skia::PaintStyle::Fill);
@@ -195,3 +430,110 @@ impl Fill {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_fill(scale_mode: ImageScaleMode) -> ImageFill {
+        match Fill::new_image_fill(Uuid::nil(), 255, (200, 100), scale_mode, 1.) {
+            Fill::Image(image_fill) => image_fill,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fill_covers_and_fit_letterboxes_the_container() {
+        let container = math::Rect::from_xywh(0., 0., 100., 100.);
+
+        let fill = image_fill(ImageScaleMode::Fill).dest_rect(&container);
+        assert_eq!(fill, math::Rect::from_xywh(-50., 0., 200., 100.));
+
+        let fit = image_fill(ImageScaleMode::Fit).dest_rect(&container);
+        assert_eq!(fit, math::Rect::from_xywh(0., 25., 100., 50.));
+    }
+
+    fn with_stops(mut fill: Fill, colors: &[Color]) -> Fill {
+        if let Fill::AngularGradient(gradient) | Fill::DiamondGradient(gradient) = &mut fill {
+            for (i, color) in colors.iter().enumerate() {
+                gradient.add_stop(*color, i as f32);
+            }
+        }
+        fill
+    }
+
+    // Paints a 100x100 rect with the fill
+    fn render_fill(fill: &Fill) -> skia::Surface {
+        let rect = math::Rect::from_wh(100., 100.);
+        let mut surface = skia::surfaces::raster_n32_premul((100, 100)).unwrap();
+        surface.canvas().draw_rect(rect, &fill.to_paint(&rect));
+        surface
+    }
+
+    fn pixel(surface: &mut skia::Surface, x: i32, y: i32) -> Color {
+        surface.peek_pixels().unwrap().get_color((x, y))
+    }
+
+    // Tells if the pixel is mostly red or mostly blue
+    fn is_reddish(color: Color) -> bool {
+        color.r() > color.b()
+    }
+
+    #[test]
+    fn angular_and_diamond_gradients_need_stops_to_be_painted() {
+        let rect = math::Rect::from_wh(100., 100.);
+        let fills = [
+            Fill::new_angular_gradient((0.5, 0.5), (1., 0.5), 1.),
+            Fill::new_diamond_gradient((0.5, 0.5), (1., 0.5), 1., 1.).unwrap(),
+        ];
+        for fill in fills {
+            let empty = fill.to_paint(&rect);
+            assert!(empty.shader().is_none());
+            assert_eq!(empty.color(), Color::TRANSPARENT);
+
+            let single = with_stops(fill.clone(), &[Color::RED]);
+            assert!(single.to_paint(&rect).shader().is_some());
+            let mut surface = render_fill(&single);
+            assert_eq!(pixel(&mut surface, 10, 90), Color::RED);
+
+            let double = with_stops(fill, &[Color::RED, Color::BLUE]);
+            assert!(double.to_paint(&rect).shader().is_some());
+        }
+    }
+
+    #[test]
+    fn angular_gradients_start_at_the_end_handle() {
+        let stops = [Color::RED, Color::BLUE];
+        // Sweeping clockwise from 3 o'clock, and from 6 o'clock
+        let mut right = render_fill(&with_stops(
+            Fill::new_angular_gradient((0.5, 0.5), (1., 0.5), 1.),
+            &stops,
+        ));
+        assert!(is_reddish(pixel(&mut right, 90, 53)));
+        assert!(!is_reddish(pixel(&mut right, 90, 47)));
+
+        let mut bottom = render_fill(&with_stops(
+            Fill::new_angular_gradient((0.5, 0.5), (0.5, 1.), 1.),
+            &stops,
+        ));
+        assert!(is_reddish(pixel(&mut bottom, 47, 90)));
+        assert!(!is_reddish(pixel(&mut bottom, 53, 90)));
+    }
+
+    #[test]
+    fn diamond_gradients_have_the_same_color_along_a_diamond() {
+        let mut surface = render_fill(&with_stops(
+            Fill::new_diamond_gradient((0.5, 0.5), (1., 0.5), 1., 1.).unwrap(),
+            &[Color::RED, Color::BLUE],
+        ));
+        assert!(is_reddish(pixel(&mut surface, 50, 50)));
+        assert!(!is_reddish(pixel(&mut surface, 95, 50)));
+
+        // Both at a diamond distance of 40 from the center, while a circle would not reach
+        // the diagonal one
+        let axis = pixel(&mut surface, 90, 50);
+        let diagonal = pixel(&mut surface, 70, 70);
+        assert!(axis.r().abs_diff(diagonal.r()) <= 3);
+        assert!(axis.b().abs_diff(diagonal.b()) <= 3);
+    }
+}
@@ -0,0 +1,11 @@
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Group {
+    // The bottom child is the mask of the rest
+    pub masked: bool,
+}
+
+impl Group {
+    pub fn new(masked: bool) -> Self {
+        Group { masked }
+    }
+}
@@ -13,6 +13,27 @@ pub struct RawPathData {
 }
 
 impl RawPathData {
+    /// Splits a buffer into its segments, failing if it is not made of whole segments.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Vec<Self>, String> {
+        let segment_size = std::mem::size_of::<Self>();
+        if bytes.len() % segment_size != 0 {
+            return Err(format!(
+                "Error deserializing path. The size of the buffer ({}) is not a multiple of {}",
+                bytes.len(),
+                segment_size
+            ));
+        }
+
+        bytes
+            .chunks_exact(segment_size)
+            .map(|data| {
+                Ok(Self {
+                    data: data.try_into().map_err(stringify_slice_err)?,
+                })
+            })
+            .collect()
+    }
+
     fn command(&self) -> Result<u16, String> {
         let cmd = u16::from_be_bytes(self.data[0..2].try_into().map_err(stringify_slice_err)?);
         Ok(cmd)
@@ -136,6 +157,19 @@ impl TryFrom<Vec<RawPathData>> for Path {
     }
 }
 
+// Paths computed by the renderer itself (like bool results) don't come from
+// serialized segments, so we only keep the skia path around.
+impl From<skia::Path> for Path {
+    fn from(skia_path: skia::Path) -> Self {
+        let open = !skia_path.is_last_contour_closed();
+        Path {
+            segments: vec![],
+            skia_path,
+            open,
+        }
+    }
+}
+
 impl Path {
     pub fn to_skia_path(&self) -> skia::Path {
         self.skia_path.snapshot()
@@ -0,0 +1,431 @@
+use skia_safe as skia;
+use uuid::Uuid;
+
+use super::{
+    BoolType, Dash, Fill, FontStyle, Group, Kind, Paragraph, RawPathData, RawStopData, SVGRaw,
+    Shadow, ShadowStyle, Shape, Stroke, TextAlign, TextContent, TextDecoration, TextLeaf,
+    TextTransform,
+};
+use crate::math;
+use crate::render::BlendMode;
+use crate::utils::uuid_from_u32_quartet_bytes;
+
+/// Version of the shape records read by [read_shapes]. It has to be increased whenever
+/// their layout changes (see `docs/serialization.md`).
+pub const SHAPE_RECORD_VERSION: u8 = 2;
+
+const FLAG_HIDDEN: u8 = 1;
+const FLAG_CLIP_CONTENT: u8 = 1 << 1;
+const FLAG_BLUR_HIDDEN: u8 = 1 << 2;
+const FLAG_MASKED: u8 = 1 << 3;
+
+const FILL_FLAG_CROP: u8 = 1;
+
+// Reads the little endian values of a buffer one after another
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, offset: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        const UNEXPECTED_END: &str = "Unexpected end of the shape records";
+        // Lengths are read from the records, so they could overflow the offset
+        let end = self.offset.checked_add(len).ok_or(UNEXPECTED_END)?;
+        let slice = self.bytes.get(self.offset..end).ok_or(UNEXPECTED_END)?;
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), String> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn count(&mut self) -> Result<usize, String> {
+        Ok(self.u32()? as usize)
+    }
+
+    fn uuid(&mut self) -> Result<Uuid, String> {
+        Ok(uuid_from_u32_quartet_bytes(self.take(16)?))
+    }
+
+    fn color(&mut self) -> Result<skia::Color, String> {
+        Ok(skia::Color::new(self.u32()?))
+    }
+
+    fn point(&mut self) -> Result<(f32, f32), String> {
+        Ok((self.f32()?, self.f32()?))
+    }
+
+    fn rect(&mut self) -> Result<(f32, f32, f32, f32), String> {
+        Ok((self.f32()?, self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    // Reads an UTF-8 string up to the next null byte, which is skipped
+    fn string(&mut self) -> Result<String, String> {
+        let rest = &self.bytes[self.offset..];
+        let len = rest
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("Unterminated string in the shape records")?;
+        let string = String::from_utf8(rest[..len].to_vec()).map_err(|e| e.to_string())?;
+        self.offset += len + 1;
+        Ok(string)
+    }
+}
+
+/// Decodes `count` shape records, laid out one after another. Each of them holds all the
+/// properties of a shape.
+pub fn read_shapes(bytes: &[u8], count: usize) -> Result<Vec<Shape>, String> {
+    let mut reader = Reader::new(bytes);
+    (0..count).map(|_| read_shape(&mut reader)).collect()
+}
+
+fn read_shape(reader: &mut Reader) -> Result<Shape, String> {
+    let version = reader.u8()?;
+    if version != SHAPE_RECORD_VERSION {
+        return Err(format!("Unsupported shape record version {}", version));
+    }
+
+    let raw_kind = reader.u8()?;
+    let flags = reader.u8()?;
+    let bool_type = BoolType::from(reader.u8()?);
+    let mut shape = Shape::new(reader.uuid()?);
+
+    shape.set_kind(match raw_kind {
+        0 => Kind::Rect(math::Rect::new_empty(), None),
+        1 => Kind::Circle(math::Rect::new_empty()),
+        2 => Kind::Path(Default::default()),
+        3 => Kind::Bool(bool_type, Default::default()),
+        4 => Kind::SVGRaw(SVGRaw::from_content(String::new())),
+        5 => Kind::Text(TextContent::default()),
+        6 => Kind::Frame(math::Rect::new_empty(), None),
+        7 => Kind::Group(Group::new(flags & FLAG_MASKED != 0)),
+        _ => return Err(format!("Unknown shape kind {}", raw_kind)),
+    });
+
+    let (left, top, right, bottom) = reader.rect()?;
+    shape.set_selrect(left, top, right, bottom);
+    let (a, b, c, d) = reader.rect()?;
+    let (e, f) = reader.point()?;
+    shape.set_transform(a, b, c, d, e, f);
+    shape.set_rotation(reader.f32()?);
+    shape.set_opacity(reader.f32()?);
+    shape.set_blend_mode(BlendMode::from(reader.i32()?));
+    let blur_type = reader.u8()?;
+    reader.skip(3)?;
+    shape.set_blur(blur_type, flags & FLAG_BLUR_HIDDEN != 0, reader.f32()?);
+    shape.set_hidden(flags & FLAG_HIDDEN != 0);
+    shape.set_clip(flags & FLAG_CLIP_CONTENT != 0);
+
+    let corners = reader.rect()?;
+    if let Kind::Rect(_, _) | Kind::Frame(_, _) = shape.kind {
+        shape.set_corners(corners);
+    }
+
+    let children_count = reader.count()?;
+    let fills_count = reader.count()?;
+    let strokes_count = reader.count()?;
+    let shadows_count = reader.count()?;
+    let svg_attrs_count = reader.count()?;
+    let content_size = reader.count()?;
+
+    for _ in 0..children_count {
+        shape.add_child(reader.uuid()?);
+    }
+
+    for _ in 0..fills_count {
+        let (fill, stops, crop) = read_fill(reader)?;
+        shape.add_fill(fill);
+        if !stops.is_empty() {
+            shape.add_fill_gradient_stops(stops)?;
+        }
+        if let Some(crop) = crop {
+            shape.set_image_fill_crop(crop)?;
+        }
+    }
+
+    for _ in 0..strokes_count {
+        let kind = reader.u8()?;
+        let (style, cap_start, cap_end) = (reader.u8()?, reader.u8()?, reader.u8()?);
+        let width = reader.f32()?;
+        let dash_offset = reader.f32()?;
+        let dash_intervals = (0..reader.count()?)
+            .map(|_| reader.f32())
+            .collect::<Result<Vec<_>, String>>()?;
+        shape.add_stroke(match kind {
+            1 => Stroke::new_inner_stroke(width, style, cap_start, cap_end),
+            2 => Stroke::new_outer_stroke(width, style, cap_start, cap_end),
+            _ => Stroke::new_center_stroke(width, style, cap_start, cap_end),
+        });
+        shape.set_stroke_dash(Dash::new(dash_intervals, dash_offset))?;
+
+        let (fill, stops, crop) = read_fill(reader)?;
+        shape.set_stroke_fill(fill)?;
+        if !stops.is_empty() {
+            shape.add_stroke_gradient_stops(stops)?;
+        }
+        if let Some(crop) = crop {
+            shape.set_image_stroke_crop(crop)?;
+        }
+    }
+
+    for _ in 0..shadows_count {
+        let color = reader.color()?;
+        let blur = reader.f32()?;
+        let spread = reader.f32()?;
+        let offset = reader.point()?;
+        let style = ShadowStyle::from(reader.u8()?);
+        let hidden = reader.u8()? != 0;
+        reader.skip(2)?;
+        shape.add_shadow(Shadow::new(color, blur, spread, offset, style, hidden));
+    }
+
+    for _ in 0..svg_attrs_count {
+        let name = reader.string()?;
+        let value = reader.string()?;
+        shape.set_svg_attr(name, value);
+    }
+
+    let content = reader.take(content_size)?;
+    match shape.kind {
+        Kind::Path(_) | Kind::Bool(_, _) => {
+            shape.set_path_segments(RawPathData::from_bytes(content)?)?;
+        }
+        Kind::SVGRaw(_) => {
+            let svg = String::from_utf8(content.to_vec()).map_err(|e| e.to_string())?;
+            shape.set_svg_raw_content(svg.trim_end_matches('\0').to_string())?;
+        }
+        Kind::Text(_) => read_text_content(&mut Reader::new(content), &mut shape)?,
+        Kind::Rect(_, _) | Kind::Circle(_) | Kind::Frame(_, _) | Kind::Group(_) => {}
+    }
+
+    Ok(shape)
+}
+
+type RawCrop = (f32, f32, f32, f32);
+
+// Returns the fill along with its gradient stops and image crop, which are added to the shape
+// after the fill itself
+fn read_fill(reader: &mut Reader) -> Result<(Fill, Vec<RawStopData>, Option<RawCrop>), String> {
+    let fill_type = reader.u8()?;
+    let scale_mode = reader.u8()?;
+    let flags = reader.u8()?;
+    let stops_count = reader.u8()? as usize;
+    let color = reader.color()?;
+    let opacity = reader.f32()?;
+    let handles = reader.take(16)?;
+    let width = reader.f32()?;
+    let size = (reader.i32()?, reader.i32()?);
+    let crop = reader.rect()?;
+
+    let mut handles_reader = Reader::new(handles);
+    let (start, end) = (handles_reader.point()?, handles_reader.point()?);
+    let fill = match fill_type {
+        0 => Fill::Solid(color),
+        1 => Fill::new_linear_gradient(start, end, opacity),
+        2 => Fill::new_radial_gradient(start, end, opacity, width),
+        3 => Fill::new_angular_gradient(start, end, opacity),
+        4 => Fill::new_diamond_gradient(start, end, opacity, width)?,
+        5 => Fill::new_image_fill(
+            uuid_from_u32_quartet_bytes(handles),
+            (opacity * 0xff as f32).floor() as u8,
+            size,
+            scale_mode.into(),
+            width,
+        ),
+        _ => return Err(format!("Unknown fill type {}", fill_type)),
+    };
+
+    let stops = (0..stops_count)
+        .map(|_| reader.array::<5>().map(RawStopData::from))
+        .collect::<Result<Vec<_>, String>>()?;
+    let crop = (fill_type == 5 && flags & FILL_FLAG_CROP != 0).then_some(crop);
+
+    Ok((fill, stops, crop))
+}
+
+fn read_text_content(reader: &mut Reader, shape: &mut Shape) -> Result<(), String> {
+    for _ in 0..reader.count()? {
+        shape.add_text_paragraph_set()?;
+        for _ in 0..reader.count()? {
+            let text_align = TextAlign::from(reader.u8()?);
+            reader.skip(3)?;
+            shape.add_text_paragraph(Paragraph::new(text_align))?;
+            for _ in 0..reader.count()? {
+                let font_weight = reader.i32()?;
+                let font_style = FontStyle::from(reader.u8()?);
+                let decoration = TextDecoration::from(reader.u8()?);
+                let transform = TextTransform::from(reader.u8()?);
+                reader.skip(1)?;
+                let font_size = reader.f32()?;
+                let line_height = reader.f32()?;
+                let letter_spacing = reader.f32()?;
+                let color = reader.color()?;
+                let text = reader.string()?;
+                let font_family = reader.string()?;
+                shape.add_text_leaf(TextLeaf {
+                    text,
+                    font_family,
+                    font_weight,
+                    font_style,
+                    font_size,
+                    line_height,
+                    letter_spacing,
+                    decoration,
+                    transform,
+                    color,
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::uuids_to_u32_quartet_bytes;
+
+    fn header(kind: u8, flags: u8, id: Uuid, counts: [u32; 6]) -> Vec<u8> {
+        let mut bytes = vec![SHAPE_RECORD_VERSION, kind, flags, 0];
+        bytes.extend(uuids_to_u32_quartet_bytes(&[id]));
+        // Selrect, transform, rotation and opacity
+        let values = [10., 20., 110., 70., 1., 0., 0., 1., 5., 0., 0., 0.5];
+        bytes.extend(values.iter().flat_map(|v: &f32| v.to_le_bytes()));
+        bytes.extend(3_i32.to_le_bytes());
+        bytes.extend([1, 0, 0, 0]);
+        bytes.extend(4_f32.to_le_bytes());
+        bytes.extend([2_f32, 2., 0., 0.].iter().flat_map(|v| v.to_le_bytes()));
+        bytes.extend(counts.iter().flat_map(|v| v.to_le_bytes()));
+        bytes
+    }
+
+    fn gradient_fill(stops: &[[u8; 5]]) -> Vec<u8> {
+        let mut bytes = vec![1, 0, 0, stops.len() as u8];
+        bytes.extend(0_u32.to_le_bytes());
+        bytes.extend([1_f32, 0., 0., 1., 1.].iter().flat_map(|v| v.to_le_bytes()));
+        bytes.extend([0_u8; 4 + 8 + 16]);
+        bytes.extend(stops.iter().flatten());
+        bytes
+    }
+
+    #[test]
+    fn lengths_past_the_end_of_the_records_are_rejected() {
+        let mut reader = Reader::new(&[1, 2, 3]);
+        reader.skip(1).unwrap();
+        assert!(reader.take(usize::MAX).is_err());
+        assert!(reader.take(3).is_err());
+        assert_eq!(reader.take(2), Ok(&[2, 3][..]));
+    }
+
+    #[test]
+    fn read_shapes_decodes_every_property() {
+        let (id, child_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut bytes = header(0, FLAG_CLIP_CONTENT, id, [1, 1, 1, 1, 0, 0]);
+        bytes.extend(uuids_to_u32_quartet_bytes(&[child_id]));
+        bytes.extend(gradient_fill(&[[255, 0, 0, 255, 0], [0, 0, 255, 255, 100]]));
+        bytes.extend([2, 2, 0, 0]);
+        bytes.extend([3_f32, 1.].iter().flat_map(|v| v.to_le_bytes()));
+        bytes.extend(2_u32.to_le_bytes());
+        bytes.extend([4_f32, 2.].iter().flat_map(|v| v.to_le_bytes()));
+        bytes.extend(gradient_fill(&[]));
+        bytes.extend(0xff000000_u32.to_le_bytes());
+        bytes.extend([4_f32, 0., 2., 2.].iter().flat_map(|v| v.to_le_bytes()));
+        bytes.extend([0, 0, 0, 0]);
+
+        let shapes = read_shapes(&bytes, 1).unwrap();
+        let shape = &shapes[0];
+
+        let selrect = math::Rect::from_ltrb(10., 20., 110., 70.);
+        let corners = Some([
+            (2., 2.).into(),
+            (2., 2.).into(),
+            (0., 0.).into(),
+            (0., 0.).into(),
+        ]);
+        assert_eq!(shape.id, id);
+        assert_eq!(shape.kind, Kind::Rect(selrect, corners));
+        assert_eq!(shape.transform.to_skia_matrix().translate_x(), 5.);
+        assert_eq!(shape.opacity, 0.5);
+        assert!(shape.clip_content && !shape.hidden);
+        assert_eq!(shape.blur.value, 4.);
+        assert_eq!(shape.children, vec![child_id]);
+
+        let mut gradient = Fill::new_linear_gradient((0., 0.), (1., 1.), 1.);
+        if let Fill::LinearGradient(g) = &mut gradient {
+            g.add_stop(skia::Color::RED, 0.);
+            g.add_stop(skia::Color::BLUE, 1.);
+        }
+        assert_eq!(shape.fills, vec![gradient]);
+        assert_eq!(shape.strokes[0].width, 3.);
+        assert_eq!(shape.strokes[0].delta(), 3.);
+        assert_eq!(shape.strokes[0].dash, Dash::new(vec![4., 2.], 1.));
+        assert_eq!(shape.drop_shadows().count(), 1);
+    }
+
+    #[test]
+    fn read_shapes_decodes_text_content_and_following_records() {
+        let mut content = vec![];
+        content.extend(1_u32.to_le_bytes());
+        content.extend(1_u32.to_le_bytes());
+        content.extend([1, 0, 0, 0]);
+        content.extend(1_u32.to_le_bytes());
+        content.extend(700_i32.to_le_bytes());
+        content.extend([1, 1, 0, 0]);
+        content.extend([16_f32, 1.2, 0.].iter().flat_map(|v| v.to_le_bytes()));
+        content.extend(0xff000000_u32.to_le_bytes());
+        content.extend(b"Hello\0sourcesanspro\0");
+
+        let (text_id, rect_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut bytes = header(
+            5,
+            FLAG_HIDDEN,
+            text_id,
+            [0, 0, 0, 0, 0, content.len() as u32],
+        );
+        bytes.extend(content);
+        bytes.extend(header(0, 0, rect_id, [0; 6]));
+
+        let shapes = read_shapes(&bytes, 2).unwrap();
+        assert!(shapes[0].hidden);
+        let paragraphs: Vec<_> = match &shapes[0].kind {
+            Kind::Text(content) => content.paragraphs().cloned().collect(),
+            kind => panic!("Unexpected kind {:?}", kind),
+        };
+        assert_eq!(paragraphs.len(), 1);
+        assert_eq!(paragraphs[0].text_align, TextAlign::Center);
+        assert_eq!(paragraphs[0].leaves[0].text, "Hello");
+        assert_eq!(paragraphs[0].leaves[0].font_family, "sourcesanspro");
+        assert_eq!(paragraphs[0].leaves[0].font_weight, 700);
+        assert_eq!(shapes[1].id, rect_id);
+
+        assert!(read_shapes(&bytes, 3).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use skia_safe::{self as skia, image_filters};
+use skia_safe::{self as skia, color_filters, image_filters};
 
 use super::Color;
 
@@ -62,6 +62,13 @@ impl Shadow {
         self.hidden
     }
 
+    /// Returns the area covered by this shadow when cast by a shape with the given bounds.
+    pub fn visual_bounds(&self, rect: &skia::Rect) -> skia::Rect {
+        // A gaussian blur is almost transparent beyond three times its sigma
+        let outset = self.spread + self.blur * 3.;
+        rect.with_offset(self.offset).with_outset((outset, outset))
+    }
+
     pub fn to_paint(&self, dilate: bool, scale: f32) -> skia::Paint {
         let mut paint = skia::Paint::default();
         let mut filter = image_filters::drop_shadow_only(
@@ -83,4 +90,28 @@ impl Shadow {
 
         paint
     }
+
+    // To draw an inner shadow we paint the area outside the shape with the shadow
+    // color, spread it, move it and blur it, and then we keep only the part that
+    // falls inside the source alpha. There is no paint when the color would leave the
+    // shape untouched.
+    pub fn to_inner_paint(&self, scale: f32) -> Option<skia::Paint> {
+        let mut paint = skia::Paint::default();
+
+        let mut filter = image_filters::color_filter(
+            color_filters::blend(self.color, skia::BlendMode::SrcOut)?,
+            None,
+            None,
+        );
+        filter = image_filters::dilate((self.spread * scale, self.spread * scale), filter, None);
+        filter =
+            image_filters::offset((self.offset.0 * scale, self.offset.1 * scale), filter, None);
+        filter = image_filters::blur((self.blur * scale, self.blur * scale), None, filter, None);
+        filter = image_filters::blend(skia::BlendMode::SrcIn, None, filter, None);
+
+        paint.set_image_filter(filter);
+        paint.set_anti_alias(true);
+
+        Some(paint)
+    }
 }
@@ -51,6 +51,23 @@ impl From<u8> for StrokeCap {
     }
 }
 
+impl StrokeCap {
+    /// Returns the distance from the end of the path to the farthest point painted by the cap
+    /// of a stroke with the given width.
+    pub fn extent(&self, width: f32) -> f32 {
+        match self {
+            StrokeCap::None => 0.,
+            // Arrows are stroked, so they get half the width on top of the triangle
+            StrokeCap::Line => width * 20_f32.sqrt() + width / 2.,
+            StrokeCap::Triangle => width * 20_f32.sqrt(),
+            StrokeCap::Rectangle | StrokeCap::Diamond => width * 2. * std::f32::consts::SQRT_2,
+            StrokeCap::Circle => width * 2.,
+            StrokeCap::Round => width / 2.,
+            StrokeCap::Square => width * std::f32::consts::FRAC_1_SQRT_2,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StrokeKind {
     InnerStroke,
@@ -58,6 +75,105 @@ pub enum StrokeKind {
     CenterStroke,
 }
 
+/// Pattern of a dashed stroke: the lengths of the dashes and the gaps between them, one after
+/// the other, starting `offset` units into the pattern.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dash {
+    intervals: Vec<f32>,
+    offset: f32,
+}
+
+impl Dash {
+    /// Returns `None` if the pattern can't be dashed, i.e. it is empty, has negative lengths or
+    /// they add up to zero.
+    pub fn new(mut intervals: Vec<f32>, offset: f32) -> Option<Self> {
+        if intervals.iter().any(|i| !i.is_finite() || *i < 0.)
+            || intervals.iter().sum::<f32>() <= 0.
+        {
+            return None;
+        }
+        // Like in SVG, odd patterns are repeated to have as many dashes as gaps
+        if intervals.len() % 2 == 1 {
+            intervals.extend_from_within(..);
+        }
+        let offset = if offset.is_finite() { offset } else { 0. };
+        Some(Self { intervals, offset })
+    }
+
+    /// Reads the intervals, as little endian `f32`, in the given buffer.
+    pub fn from_bytes(bytes: &[u8], offset: f32) -> Result<Option<Self>, String> {
+        if bytes.len() % 4 != 0 {
+            return Err(format!(
+                "Error deserializing dash intervals. The size of the buffer ({}) is not a multiple of 4",
+                bytes.len()
+            ));
+        }
+        let intervals = bytes
+            .chunks_exact(4)
+            .map(|data| f32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+            .collect();
+        Ok(Self::new(intervals, offset))
+    }
+
+    /// Reads the `stroke-dasharray` and `stroke-dashoffset` attributes of SVG paths.
+    pub fn from_svg_attrs(svg_attrs: &HashMap<String, String>) -> Option<Self> {
+        let parse = |value: &str| value.trim().trim_end_matches("px").parse::<f32>().ok();
+        let intervals = svg_attrs
+            .get("stroke-dasharray")?
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|value| !value.is_empty())
+            .map(parse)
+            .collect::<Option<Vec<f32>>>()?;
+        let offset = svg_attrs
+            .get("stroke-dashoffset")
+            .and_then(|value| parse(value))
+            .unwrap_or(0.);
+        Self::new(intervals, offset)
+    }
+
+    /// Scales the pattern so it repeats a whole number of times along the given length.
+    pub fn fit(&self, length: f32) -> Self {
+        let scale = fit_scale(self.intervals.iter().sum(), length);
+        Self {
+            intervals: self.intervals.iter().map(|i| i * scale).collect(),
+            offset: self.offset * scale,
+        }
+    }
+
+    /// Returns the dashes, as ranges of distances, along the side of a closed contour between
+    /// two corners. The pattern is fitted to the side and starts in the middle of its first dash,
+    /// so the dashes at both ends join the ones of the next sides around the corners.
+    pub fn side_dashes(&self, length: f32) -> Vec<(f32, f32)> {
+        let fitted = self.fit(length);
+        let period: f32 = fitted.intervals.iter().sum();
+        let mut distance = -(fitted.intervals[0] / 2. + fitted.offset).rem_euclid(period);
+        let mut dashes = vec![];
+        while distance < length {
+            for (i, interval) in fitted.intervals.iter().enumerate() {
+                let (start, end) = (distance.max(0.), (distance + interval).min(length));
+                if i % 2 == 0 && end > start {
+                    dashes.push((start, end));
+                }
+                distance += interval;
+            }
+        }
+        dashes
+    }
+
+    pub fn to_path_effect(&self) -> Option<skia::PathEffect> {
+        skia::PathEffect::dash(&self.intervals, self.offset)
+    }
+}
+
+// Returns how much a period has to be scaled so it fits a whole number of times in a length
+fn fit_scale(period: f32, length: f32) -> f32 {
+    if period <= 0. || length <= 0. {
+        return 1.;
+    }
+    let count = (length / period).round().max(1.);
+    length / (count * period)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Stroke {
     pub fill: Fill,
@@ -66,6 +182,8 @@ pub struct Stroke {
     pub cap_end: StrokeCap,
     pub cap_start: StrokeCap,
     pub kind: StrokeKind,
+    // Pattern set explicitly, taking precedence over the style and the SVG attributes
+    pub dash: Option<Dash>,
 }
 
 impl Stroke {
@@ -87,6 +205,7 @@ impl Stroke {
             cap_end: StrokeCap::from(cap_end),
             cap_start: StrokeCap::from(cap_start),
             kind: StrokeKind::CenterStroke,
+            dash: None,
         }
     }
 
@@ -99,6 +218,7 @@ impl Stroke {
             cap_end: StrokeCap::from(cap_end),
             cap_start: StrokeCap::from(cap_start),
             kind: StrokeKind::InnerStroke,
+            dash: None,
         }
     }
 
@@ -111,6 +231,7 @@ impl Stroke {
             cap_end: StrokeCap::from(cap_end),
             cap_start: StrokeCap::from(cap_start),
             kind: StrokeKind::OuterStroke,
+            dash: None,
         }
     }
 
@@ -122,6 +243,18 @@ impl Stroke {
         }
     }
 
+    /// Returns how far the stroke paints out of the shape. Open paths are stroked centered,
+    /// with caps at their ends.
+    pub fn outset(&self, is_open: bool) -> f32 {
+        if is_open {
+            (self.width / 2.)
+                .max(self.cap_start.extent(self.width))
+                .max(self.cap_end.extent(self.width))
+        } else {
+            self.delta()
+        }
+    }
+
     pub fn outer_rect(&self, rect: &math::Rect) -> math::Rect {
         match self.kind {
             StrokeKind::InnerStroke => math::Rect::from_xywh(
@@ -156,6 +289,68 @@ impl Stroke {
         outer
     }
 
+    /// Returns the dash pattern of the stroke: the one set explicitly, the one in the SVG
+    /// attributes or the one of its style, in that order.
+    pub fn dash(&self, svg_attrs: &HashMap<String, String>) -> Option<Dash> {
+        if let Some(dash) = &self.dash {
+            return Some(dash.clone());
+        }
+        if let Some(dash) = Dash::from_svg_attrs(svg_attrs) {
+            return Some(dash);
+        }
+        let width = self.width;
+        match self.style {
+            StrokeStyle::Dashed => Dash::new(vec![width + 10., width + 10.], 0.),
+            StrokeStyle::Mixed => {
+                Dash::new(vec![width + 5., width + 5., width + 1., width + 5.], 0.)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the effect painting the dashes, or the dots, of the stroke. Closed contours are
+    /// patterned with their pattern fitted between their corners instead, see
+    /// [`Dash::side_dashes`].
+    pub fn path_effect(&self, svg_attrs: &HashMap<String, String>) -> Option<skia::PathEffect> {
+        if let Some(dash) = self.dash(svg_attrs) {
+            return dash.to_path_effect();
+        }
+        if self.style != StrokeStyle::Dotted {
+            return None;
+        }
+        skia::PathEffect::path_1d(
+            &self.dot_path((0., 0.)),
+            self.dot_advance(),
+            0.0,
+            skia::path_1d_path_effect::Style::Translate,
+        )
+    }
+
+    /// Returns the dot of a dotted stroke centered at the given point.
+    pub fn dot_path(&self, center: impl Into<skia::Point>) -> skia::Path {
+        let radius = match self.kind {
+            StrokeKind::InnerStroke => self.width,
+            StrokeKind::CenterStroke => self.width / 2.0,
+            StrokeKind::OuterStroke => self.width,
+        };
+        let mut circle_path = skia::Path::new();
+        circle_path.add_circle(center, radius, None);
+        circle_path
+    }
+
+    /// Returns the distance between the centers of the dots of a dotted stroke.
+    pub fn dot_advance(&self) -> f32 {
+        self.width + 5.0
+    }
+
+    /// Returns the distances of the dots along the side of a closed contour between two
+    /// corners, spaced evenly so there is one on each corner.
+    pub fn side_dots(&self, length: f32) -> Vec<f32> {
+        let advance = self.dot_advance() * fit_scale(self.dot_advance(), length);
+        let count = (length / advance).round() as usize;
+        (0..count).map(|i| i as f32 * advance).collect()
+    }
+
     pub fn to_paint(
         &self,
         rect: &math::Rect,
@@ -182,40 +377,7 @@ impl Stroke {
             paint.set_stroke_join(skia::paint::Join::Round);
         }
 
-        if self.style != StrokeStyle::Solid {
-            let path_effect = match self.style {
-                StrokeStyle::Dotted => {
-                    let mut circle_path = skia::Path::new();
-                    let width = match self.kind {
-                        StrokeKind::InnerStroke => self.width,
-                        StrokeKind::CenterStroke => self.width / 2.0,
-                        StrokeKind::OuterStroke => self.width,
-                    };
-                    circle_path.add_circle((0.0, 0.0), width, None);
-                    let advance = self.width + 5.0;
-                    skia::PathEffect::path_1d(
-                        &circle_path,
-                        advance,
-                        0.0,
-                        skia::path_1d_path_effect::Style::Translate,
-                    )
-                }
-                StrokeStyle::Dashed => {
-                    skia::PathEffect::dash(&[self.width + 10., self.width + 10.], 0.)
-                }
-                StrokeStyle::Mixed => skia::PathEffect::dash(
-                    &[
-                        self.width + 5.,
-                        self.width + 5.,
-                        self.width + 1.,
-                        self.width + 5.,
-                    ],
-                    0.,
-                ),
-                _ => None,
-            };
-            paint.set_path_effect(path_effect);
-        }
+        paint.set_path_effect(self.path_effect(svg_attrs));
 
         paint
     }
@@ -241,3 +403,30 @@ impl Stroke {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn svg_dash_arrays_are_repeated_to_even_length_and_fitted_to_closed_contours() {
+        let svg_attrs = HashMap::from([
+            ("stroke-dasharray".to_string(), "4, 2 4".to_string()),
+            ("stroke-dashoffset".to_string(), "1".to_string()),
+        ]);
+        let dash = Dash::from_svg_attrs(&svg_attrs).unwrap();
+        assert_eq!(dash, Dash::new(vec![4., 2., 4., 4., 2., 4.], 1.).unwrap());
+
+        // A period of 20 fits 5 times in 110 once scaled to 22
+        let fitted = dash.fit(110.);
+        assert!((fitted.intervals.iter().sum::<f32>() - 22.).abs() < 1e-4);
+        assert!((fitted.offset - 1.1).abs() < 1e-4);
+
+        // Both ends of a side get half of the first dash
+        let dashes = Dash::new(vec![10., 30.], 0.).unwrap().side_dashes(80.);
+        assert_eq!(dashes, vec![(0., 5.), (35., 45.), (75., 80.)]);
+
+        let none = HashMap::from([("stroke-dasharray".to_string(), "none".to_string())]);
+        assert_eq!(Dash::from_svg_attrs(&none), None);
+    }
+}
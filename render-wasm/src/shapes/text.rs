@@ -0,0 +1,240 @@
+use skia_safe::{self as skia, textlayout};
+
+use super::Color;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+    Justify,
+}
+
+impl From<u8> for TextAlign {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Center,
+            2 => Self::Right,
+            3 => Self::Justify,
+            _ => Self::Left,
+        }
+    }
+}
+
+impl From<TextAlign> for textlayout::TextAlign {
+    fn from(value: TextAlign) -> Self {
+        match value {
+            TextAlign::Left => Self::Left,
+            TextAlign::Center => Self::Center,
+            TextAlign::Right => Self::Right,
+            TextAlign::Justify => Self::Justify,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextDecoration {
+    None,
+    Underline,
+    LineThrough,
+}
+
+impl From<u8> for TextDecoration {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Underline,
+            2 => Self::LineThrough,
+            _ => Self::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextTransform {
+    None,
+    Uppercase,
+    Lowercase,
+    Capitalize,
+}
+
+impl From<u8> for TextTransform {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Uppercase,
+            2 => Self::Lowercase,
+            3 => Self::Capitalize,
+            _ => Self::None,
+        }
+    }
+}
+
+impl TextTransform {
+    pub fn apply(&self, text: &str) -> String {
+        match self {
+            Self::None => text.to_string(),
+            Self::Uppercase => text.to_uppercase(),
+            Self::Lowercase => text.to_lowercase(),
+            Self::Capitalize => {
+                let mut capitalize_next = true;
+                text.chars()
+                    .flat_map(|c| {
+                        let res: Vec<char> = if capitalize_next {
+                            c.to_uppercase().collect()
+                        } else {
+                            vec![c]
+                        };
+                        capitalize_next = c.is_whitespace();
+                        res
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FontStyle {
+    Normal,
+    Italic,
+}
+
+impl From<u8> for FontStyle {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Self::Italic,
+            _ => Self::Normal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextLeaf {
+    pub text: String,
+    pub font_family: String,
+    pub font_weight: i32,
+    pub font_style: FontStyle,
+    pub font_size: f32,
+    pub line_height: f32,
+    pub letter_spacing: f32,
+    pub decoration: TextDecoration,
+    pub transform: TextTransform,
+    pub color: Color,
+}
+
+impl TextLeaf {
+    pub fn to_style(&self) -> textlayout::TextStyle {
+        let mut style = textlayout::TextStyle::new();
+        let slant = match self.font_style {
+            FontStyle::Normal => skia::font_style::Slant::Upright,
+            FontStyle::Italic => skia::font_style::Slant::Italic,
+        };
+
+        style.set_color(self.color);
+        style.set_font_families(&[&self.font_family]);
+        style.set_font_style(skia::FontStyle::new(
+            self.font_weight.into(),
+            skia::font_style::Width::NORMAL,
+            slant,
+        ));
+        style.set_font_size(self.font_size);
+        // Line height is a multiplier of the font size, like in CSS
+        style.set_height(self.line_height);
+        style.set_height_override(true);
+        style.set_letter_spacing(self.letter_spacing);
+
+        match self.decoration {
+            TextDecoration::None => {}
+            TextDecoration::Underline => {
+                style.set_decoration_type(textlayout::TextDecoration::UNDERLINE)
+            }
+            TextDecoration::LineThrough => {
+                style.set_decoration_type(textlayout::TextDecoration::LINE_THROUGH)
+            }
+        }
+
+        style
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paragraph {
+    pub text_align: TextAlign,
+    pub leaves: Vec<TextLeaf>,
+}
+
+impl Paragraph {
+    pub fn new(text_align: TextAlign) -> Self {
+        Self {
+            text_align,
+            leaves: vec![],
+        }
+    }
+
+    pub fn to_skia_paragraph(&self, fonts: &textlayout::FontCollection) -> textlayout::Paragraph {
+        let mut paragraph_style = textlayout::ParagraphStyle::new();
+        paragraph_style.set_text_align(self.text_align.into());
+
+        let mut builder = textlayout::ParagraphBuilder::new(&paragraph_style, fonts.clone());
+        for leaf in self.leaves.iter() {
+            builder.push_style(&leaf.to_style());
+            builder.add_text(leaf.transform.apply(&leaf.text));
+            builder.pop();
+        }
+
+        builder.build()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ParagraphSet {
+    pub paragraphs: Vec<Paragraph>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TextContent {
+    pub paragraph_sets: Vec<ParagraphSet>,
+}
+
+impl TextContent {
+    pub fn add_paragraph_set(&mut self) {
+        self.paragraph_sets.push(ParagraphSet::default());
+    }
+
+    pub fn add_paragraph(&mut self, paragraph: Paragraph) -> Result<(), String> {
+        let paragraph_set = self
+            .paragraph_sets
+            .last_mut()
+            .ok_or("Text has no paragraph sets")?;
+        paragraph_set.paragraphs.push(paragraph);
+        Ok(())
+    }
+
+    pub fn add_leaf(&mut self, leaf: TextLeaf) -> Result<(), String> {
+        let paragraph = self
+            .paragraph_sets
+            .last_mut()
+            .and_then(|set| set.paragraphs.last_mut())
+            .ok_or("Text has no paragraphs")?;
+        paragraph.leaves.push(leaf);
+        Ok(())
+    }
+
+    pub fn paragraphs(&self) -> impl Iterator<Item = &Paragraph> {
+        self.paragraph_sets
+            .iter()
+            .flat_map(|set| set.paragraphs.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capitalize_uppercases_the_first_letter_of_each_word() {
+        assert_eq!(
+            TextTransform::Capitalize.apply("hello wasm world"),
+            "Hello Wasm World"
+        );
+    }
+}
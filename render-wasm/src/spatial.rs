@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::math::Rect;
+use crate::shapes::Shape;
+
+// Nodes are split in four when they hold more items than this
+const MAX_ITEMS: usize = 16;
+const MAX_DEPTH: usize = 12;
+// The root grows when an item does not fit in it
+const INITIAL_SIZE: f32 = 4096.;
+
+fn contains(outer: &Rect, inner: &Rect) -> bool {
+    outer.left <= inner.left
+        && outer.top <= inner.top
+        && outer.right >= inner.right
+        && outer.bottom >= inner.bottom
+}
+
+fn quadrants(rect: &Rect) -> [Rect; 4] {
+    let center = rect.center();
+    [
+        Rect::from_ltrb(rect.left, rect.top, center.x, center.y),
+        Rect::from_ltrb(center.x, rect.top, rect.right, center.y),
+        Rect::from_ltrb(rect.left, center.y, center.x, rect.bottom),
+        Rect::from_ltrb(center.x, center.y, rect.right, rect.bottom),
+    ]
+}
+
+struct Node {
+    rect: Rect,
+    // Items that do not fit in a single quadrant, or all of them in leaves
+    items: Vec<Uuid>,
+    children: Option<Box<[Node; 4]>>,
+}
+
+impl Node {
+    fn new(rect: Rect) -> Self {
+        Self {
+            rect,
+            items: vec![],
+            children: None,
+        }
+    }
+
+    fn quadrant_of(&self, bounds: &Rect) -> Option<usize> {
+        quadrants(&self.rect)
+            .iter()
+            .position(|quadrant| contains(quadrant, bounds))
+    }
+
+    fn insert(&mut self, id: Uuid, bounds: &Rect, all_bounds: &HashMap<Uuid, Rect>, depth: usize) {
+        if let Some(index) = self.quadrant_of(bounds) {
+            if let Some(children) = self.children.as_mut() {
+                children[index].insert(id, bounds, all_bounds, depth + 1);
+                return;
+            }
+        }
+
+        self.items.push(id);
+        if self.children.is_none() && self.items.len() > MAX_ITEMS && depth < MAX_DEPTH {
+            self.split(all_bounds, depth);
+        }
+    }
+
+    fn split(&mut self, all_bounds: &HashMap<Uuid, Rect>, depth: usize) {
+        self.children = Some(Box::new(quadrants(&self.rect).map(Node::new)));
+        for id in std::mem::take(&mut self.items) {
+            self.insert(id, &all_bounds[&id], all_bounds, depth);
+        }
+    }
+
+    fn remove(&mut self, id: &Uuid, bounds: &Rect) -> bool {
+        if let Some(index) = self.quadrant_of(bounds) {
+            if let Some(children) = self.children.as_mut() {
+                return children[index].remove(id, bounds);
+            }
+        }
+
+        match self.items.iter().position(|item| item == id) {
+            Some(index) => {
+                self.items.swap_remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn query(&self, area: &Rect, all_bounds: &HashMap<Uuid, Rect>, result: &mut Vec<Uuid>) {
+        if !self.rect.intersects(area) {
+            return;
+        }
+
+        result.extend(
+            self.items
+                .iter()
+                .filter(|id| all_bounds[*id].intersects(area)),
+        );
+
+        if let Some(children) = &self.children {
+            for child in children.iter() {
+                child.query(area, all_bounds, result);
+            }
+        }
+    }
+}
+
+/// Quadtree of ids by their bounds, to find the ones in an area in logarithmic time.
+pub struct QuadTree {
+    root: Node,
+    bounds: HashMap<Uuid, Rect>,
+}
+
+impl QuadTree {
+    pub fn new() -> Self {
+        let offset = -INITIAL_SIZE / 2.;
+        Self {
+            root: Node::new(Rect::from_xywh(offset, offset, INITIAL_SIZE, INITIAL_SIZE)),
+            bounds: HashMap::new(),
+        }
+    }
+
+    /// Adds an item, or moves it if it was already in the tree.
+    pub fn insert(&mut self, id: Uuid, bounds: Rect) {
+        self.remove(&id);
+        if !bounds.is_finite() {
+            return;
+        }
+
+        self.bounds.insert(id, bounds);
+        if contains(&self.root.rect, &bounds) {
+            self.root.insert(id, &bounds, &self.bounds, 0);
+        } else {
+            self.grow(&bounds);
+        }
+    }
+
+    pub fn remove(&mut self, id: &Uuid) {
+        if let Some(bounds) = self.bounds.remove(id) {
+            self.root.remove(id, &bounds);
+        }
+    }
+
+    /// Returns the items whose bounds intersect the area.
+    pub fn query(&self, area: &Rect) -> Vec<Uuid> {
+        let mut result = vec![];
+        self.root.query(area, &self.bounds, &mut result);
+        result
+    }
+
+    // Doubles the root until the bounds fit in it, and inserts all the items again.
+    fn grow(&mut self, bounds: &Rect) {
+        let mut rect = self.root.rect;
+        while !contains(&rect, bounds) {
+            rect.outset((rect.width() / 2., rect.height() / 2.));
+        }
+
+        self.root = Node::new(rect);
+        for (id, bounds) in self.bounds.iter() {
+            self.root.insert(*id, bounds, &self.bounds, 0);
+        }
+    }
+}
+
+/// Spatial index of the shapes by their visual bounds, so culling and spatial queries do not
+/// need to go through the whole tree. It has to be updated when the shapes change.
+pub struct SpatialIndex {
+    quadtree: QuadTree,
+    parents: HashMap<Uuid, Uuid>,
+    // Children of each shape when it was last indexed, to unlink the ones that are gone
+    children: HashMap<Uuid, Vec<Uuid>>,
+}
+
+impl SpatialIndex {
+    pub fn new() -> Self {
+        Self {
+            quadtree: QuadTree::new(),
+            parents: HashMap::new(),
+            children: HashMap::new(),
+        }
+    }
+
+    pub fn rebuild(&mut self, shapes: &HashMap<Uuid, Shape>) {
+        *self = Self::new();
+        for shape in shapes.values() {
+            self.update(shape);
+        }
+    }
+
+    /// Indexes the current bounds and children of the shape.
+    pub fn update(&mut self, shape: &Shape) {
+        // The root is never painted
+        if !shape.id.is_nil() {
+            self.quadtree.insert(shape.id, shape.visual_bounds());
+        }
+        self.unlink_children(&shape.id);
+        for child_id in shape.children.iter() {
+            self.parents.insert(*child_id, shape.id);
+        }
+        self.children.insert(shape.id, shape.children.clone());
+    }
+
+    pub fn remove(&mut self, id: &Uuid) {
+        self.quadtree.remove(id);
+        self.parents.remove(id);
+        self.unlink_children(id);
+    }
+
+    // Removes the links to the indexed children of the shape, unless they were moved to
+    // another parent in the meantime.
+    fn unlink_children(&mut self, id: &Uuid) {
+        for child_id in self.children.remove(id).unwrap_or_default() {
+            if self.parents.get(&child_id) == Some(id) {
+                self.parents.remove(&child_id);
+            }
+        }
+    }
+
+    pub fn parent(&self, id: &Uuid) -> Option<Uuid> {
+        self.parents.get(id).copied()
+    }
+
+    /// Returns the shapes that paint inside the area, not including their children.
+    pub fn query(&self, area: &Rect) -> Vec<Uuid> {
+        self.quadtree.query(area)
+    }
+
+    /// Returns the shapes that paint inside the area along with their ancestors, which need
+    /// to be traversed to render them.
+    pub fn visible_shapes(&self, area: &Rect) -> HashSet<Uuid> {
+        let mut visible = HashSet::new();
+        for id in self.query(area) {
+            let mut current = Some(id);
+            while let Some(id) = current {
+                if !visible.insert(id) {
+                    break;
+                }
+                current = self.parents.get(&id).copied();
+            }
+        }
+        visible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_returns_the_items_in_the_area_after_splitting_and_growing() {
+        let mut quadtree = QuadTree::new();
+        let ids: Vec<_> = (0..100)
+            .map(|i| {
+                let id = Uuid::new_v4();
+                quadtree.insert(id, Rect::from_xywh(i as f32 * 100., 0., 50., 50.));
+                id
+            })
+            .collect();
+
+        let found: HashSet<_> = quadtree
+            .query(&Rect::from_xywh(120., 10., 100., 10.))
+            .into_iter()
+            .collect();
+        assert_eq!(found, HashSet::from([ids[1], ids[2]]));
+        assert_eq!(
+            quadtree.query(&Rect::from_xywh(9910., 10., 10., 10.)),
+            vec![ids[99]]
+        );
+    }
+
+    #[test]
+    fn moved_items_are_only_found_in_their_new_bounds() {
+        let mut quadtree = QuadTree::new();
+        let id = Uuid::new_v4();
+        quadtree.insert(id, Rect::from_xywh(0., 0., 10., 10.));
+        quadtree.insert(id, Rect::from_xywh(500., 500., 10., 10.));
+
+        assert!(quadtree
+            .query(&Rect::from_xywh(0., 0., 20., 20.))
+            .is_empty());
+        assert_eq!(
+            quadtree.query(&Rect::from_xywh(490., 490., 20., 20.)),
+            vec![id]
+        );
+    }
+
+    #[test]
+    fn visible_shapes_include_their_ancestors() {
+        let (group_id, child_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(group_id);
+        let mut group = Shape::new(group_id);
+        group.set_selrect(0., 0., 10., 10.);
+        group.add_child(child_id);
+        let mut child = Shape::new(child_id);
+        child.set_selrect(100., 100., 110., 110.);
+
+        let mut index = SpatialIndex::new();
+        index.rebuild(&HashMap::from([
+            (Uuid::nil(), root),
+            (group_id, group),
+            (child_id, child),
+        ]));
+
+        let visible = index.visible_shapes(&Rect::from_xywh(90., 90., 50., 50.));
+        assert_eq!(visible, HashSet::from([child_id, group_id, Uuid::nil()]));
+    }
+
+    #[test]
+    fn removed_children_are_unlinked_from_their_former_parent() {
+        let (old_group_id, new_group_id, child_id) =
+            (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let mut old_group = Shape::new(old_group_id);
+        old_group.add_child(child_id);
+        let mut index = SpatialIndex::new();
+        index.update(&old_group);
+
+        old_group.clear_children();
+        index.update(&old_group);
+        assert_eq!(index.parent(&child_id), None);
+
+        // Moving a child keeps its new parent, whatever the order of the updates
+        old_group.add_child(child_id);
+        index.update(&old_group);
+        let mut new_group = Shape::new(new_group_id);
+        new_group.add_child(child_id);
+        index.update(&new_group);
+        old_group.clear_children();
+        index.update(&old_group);
+        assert_eq!(index.parent(&child_id), Some(new_group_id));
+    }
+}
@@ -1,10 +1,12 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use skia_safe as skia;
 use uuid::Uuid;
 
-use crate::render::RenderState;
-use crate::shapes::Shape;
+use crate::hit_test;
+use crate::math;
+use crate::render::{ExportFormat, RenderState};
+use crate::shapes::{subtree_visual_bounds, Kind, Shape};
 
 /// This struct holds the state of the Rust application between JS calls.
 ///
@@ -16,56 +18,587 @@ pub(crate) struct State<'a> {
     pub current_id: Option<Uuid>,
     pub current_shape: Option<&'a mut Shape>,
     pub shapes: HashMap<Uuid, Shape>,
+    // Shapes changed since the last frame, with the area they painted before the first change
+    dirty_shapes: HashMap<Uuid, Option<math::Rect>>,
+    // Children lists before their first change since the last frame
+    dirty_children: HashMap<Uuid, Vec<Uuid>>,
+    // Area to paint again in the next frame, besides the one of the dirty shapes
+    damage: Option<math::Rect>,
+    // Shapes whose result has to be computed again if they are bools, see `update_bool_paths`
+    stale_bools: HashSet<Uuid>,
 }
 
 impl<'a> State<'a> {
-    pub fn new(width: i32, height: i32, capacity: usize) -> Self {
-        State {
-            render_state: RenderState::new(width, height),
+    pub fn new(width: i32, height: i32, capacity: usize) -> Result<Self, String> {
+        Ok(State {
+            render_state: RenderState::new(width, height)?,
             current_id: None,
             current_shape: None,
             shapes: HashMap::with_capacity(capacity),
-        }
+            dirty_shapes: HashMap::new(),
+            dirty_children: HashMap::new(),
+            damage: None,
+            stale_bools: HashSet::new(),
+        })
+    }
+
+    /// Creates a state with a headless raster renderer, see [RenderState::new_raster].
+    pub fn new_raster(width: i32, height: i32, capacity: usize) -> Result<Self, String> {
+        Ok(State {
+            render_state: RenderState::new_raster(width, height)?,
+            current_id: None,
+            current_shape: None,
+            shapes: HashMap::with_capacity(capacity),
+            dirty_shapes: HashMap::new(),
+            dirty_children: HashMap::new(),
+            damage: None,
+            stale_bools: HashSet::new(),
+        })
     }
 
-    pub fn resize(&mut self, width: i32, height: i32) {
-        self.render_state.resize(width, height);
+    pub fn resize(&mut self, width: i32, height: i32) -> Result<(), String> {
+        self.render_state.resize(width, height)
     }
 
     pub fn render_state(&'a mut self) -> &'a mut RenderState {
         &mut self.render_state
     }
 
-    pub fn pan(&mut self) {
-        // TODO: propagate error to main fn
-        let _ = self.render_state.pan(&self.shapes).unwrap();
+    // The missing tiles are rendered with the shapes found in the index, so it has to be
+    // updated first
+    pub fn pan(&mut self) -> Result<(), String> {
+        self.update_index();
+        self.render_state.pan(&self.shapes)
+    }
+
+    pub fn zoom(&mut self) -> Result<(), String> {
+        self.update_index();
+        self.render_state.zoom(&self.shapes)
+    }
+
+    /// Renders the viewbox. Unless `invalidate_tiles` is set, only the missing tiles and the
+    /// area changed since the last frame are painted.
+    pub fn render_all(&mut self, invalidate_tiles: bool) -> Result<(), String> {
+        match self.take_damage() {
+            Some(damage) if !invalidate_tiles => {
+                self.render_state.render_damage(&self.shapes, damage)
+            }
+            _ => self.render_state.render_all(&self.shapes, invalidate_tiles),
+        }
+    }
+
+    /// Returns the area painted by the dirty shapes, before and after being changed, and
+    /// marks them as clean.
+    fn take_damage(&mut self) -> Option<math::Rect> {
+        self.update_index();
+
+        let mut damage = self.damage.take();
+        for (id, old_bounds) in self.dirty_shapes.drain() {
+            join_damage(&mut damage, old_bounds);
+            join_damage(&mut damage, subtree_visual_bounds(&id, &self.shapes));
+        }
+
+        for (id, old_children) in self.dirty_children.drain() {
+            let shape = match self.shapes.get(&id) {
+                Some(shape) => shape,
+                None => continue,
+            };
+            for child_id in changed_children(&old_children, &shape.children) {
+                join_damage(&mut damage, subtree_visual_bounds(child_id, &self.shapes));
+            }
+        }
+
+        damage
+    }
+
+    /// Indexes the current bounds of the dirty shapes.
+    fn update_index(&mut self) {
+        self.update_bool_paths();
+        let dirty_ids = self.dirty_shapes.keys().chain(self.dirty_children.keys());
+        for shape in dirty_ids.filter_map(|id| self.shapes.get(id)) {
+            self.render_state.index.update(shape);
+        }
+    }
+
+    /// Computes the result of the bools whose children have changed, and stores it as their
+    /// path. Bools are computed out of their children, so editing any of them updates the
+    /// result without having to send the path again.
+    fn update_bool_paths(&mut self) {
+        for id in std::mem::take(&mut self.stale_bools) {
+            let shape = match self.shapes.get(&id) {
+                Some(shape) => shape,
+                None => continue,
+            };
+            let bool_type = match shape.kind {
+                Kind::Bool(bool_type, _) => bool_type,
+                _ => continue,
+            };
+            let path = self
+                .render_state
+                .cache
+                .get(&id)
+                .bool_path(shape, &self.shapes)
+                .unwrap_or_default();
+            if let Some(shape) = self.shapes.get_mut(&id) {
+                shape.set_kind(Kind::Bool(bool_type, path));
+            }
+        }
+    }
+
+    /// Returns the area painted by the shape and its children, in world coordinates.
+    pub fn shape_visual_bounds(&self, id: Uuid) -> Option<math::Rect> {
+        subtree_visual_bounds(&id, &self.shapes)
+    }
+
+    /// Returns the visible shapes under the given point, in world coordinates, topmost first.
+    pub fn shapes_at(&mut self, x: f32, y: f32) -> Vec<Uuid> {
+        self.update_index();
+        hit_test::shapes_at((x, y), &self.shapes, &self.render_state.index)
+    }
+
+    /// Returns the visible shapes inside (or, unless `contained` is set, crossing) the given
+    /// rect in world coordinates, topmost first.
+    pub fn shapes_in_rect(&mut self, rect: math::Rect, contained: bool) -> Vec<Uuid> {
+        self.update_index();
+        hit_test::shapes_in_rect(rect, contained, &self.shapes, &self.render_state.index)
+    }
+
+    fn mark_dirty(&mut self, id: Uuid) {
+        // The shape could have been rendered since it was marked, e.g. by an export
+        self.invalidate_cache(id);
+
+        // The root is not painted, changing its children is tracked apart
+        if id.is_nil() || self.dirty_shapes.contains_key(&id) {
+            return;
+        }
+        let old_bounds = subtree_visual_bounds(&id, &self.shapes);
+        self.dirty_shapes.insert(id, old_bounds);
+    }
+
+    /// Drops the render artifacts of the shape, and the bool results computed out of it.
+    fn invalidate_cache(&mut self, id: Uuid) {
+        let cache = &mut self.render_state.cache;
+        cache.invalidate(&id);
+        self.stale_bools.insert(id);
+        let mut parent_id = self.render_state.index.parent(&id);
+        while let Some(id) = parent_id.filter(|id| !id.is_nil()) {
+            cache.invalidate_bool_path(&id);
+            self.stale_bools.insert(id);
+            parent_id = self.render_state.index.parent(&id);
+        }
+    }
+
+    pub fn add_font(&mut self, family_name: String, font_data: &[u8]) -> Result<(), String> {
+        self.render_state.add_font(family_name, font_data)?;
+        // SVGs are parsed with the fonts available at the time
+        self.render_state.cache.clear();
+        // Texts and SVGs may have been drawn with a fallback font
+        for shape in self.shapes.values() {
+            if let Kind::Text(_) | Kind::SVGRaw(_) = shape.kind {
+                join_damage(&mut self.damage, Some(shape.visual_bounds()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_image(&mut self, id: Uuid, image_data: &[u8]) -> Result<(), String> {
+        self.render_state.add_image(id, image_data)?;
+        // Shapes using the image were painted without it
+        for shape in self.shapes.values() {
+            if shape.image_ids().any(|image_id| image_id == id) {
+                join_damage(&mut self.damage, Some(shape.visual_bounds()));
+            }
+        }
+        Ok(())
     }
 
-    pub fn zoom(&mut self) {
-        // TODO: propagate error to main fn
-        let _ = self.render_state.zoom(&self.shapes).unwrap();
+    /// Removes an image that JS does not need anymore. The shapes still using it are painted
+    /// without it.
+    pub fn release_image(&mut self, id: Uuid) {
+        if !self.render_state.has_image(&id) {
+            return;
+        }
+        self.render_state.release_image(&id);
+        for shape in self.shapes.values() {
+            if shape.image_ids().any(|image_id| image_id == id) {
+                join_damage(&mut self.damage, Some(shape.visual_bounds()));
+            }
+        }
     }
 
-    pub fn render_all(&mut self, generate_cached_surface_image: bool) {
+    pub fn export_shape(
+        &mut self,
+        id: Uuid,
+        scale: f32,
+        format: ExportFormat,
+        quality: u32,
+    ) -> Result<Vec<u8>, String> {
+        self.update_bool_paths();
         self.render_state
-            .render_all(&self.shapes, generate_cached_surface_image);
+            .export_shape(&id, &self.shapes, scale, format, quality)
+    }
+
+    pub fn export_pdf(&mut self, ids: &[Uuid]) -> Result<Vec<u8>, String> {
+        self.update_bool_paths();
+        self.render_state.export_pdf(ids, &self.shapes)
+    }
+
+    pub fn export_svg(&mut self, id: Uuid, text_to_paths: bool) -> Result<String, String> {
+        self.update_bool_paths();
+        self.render_state
+            .export_svg(&id, &self.shapes, text_to_paths)
     }
 
     pub fn use_shape(&'a mut self, id: Uuid) {
         if !self.shapes.contains_key(&id) {
             let new_shape = Shape::new(id);
             self.shapes.insert(id, new_shape);
+            // New shapes did not paint anything before
+            self.dirty_shapes.insert(id, None);
         }
 
         self.current_id = Some(id);
         self.current_shape = self.shapes.get_mut(&id);
     }
 
+    /// Returns the shape selected with [State::use_shape] to change it, so it is marked as
+    /// dirty. Its children have to be changed with [State::add_shape_child] and
+    /// [State::clear_shape_children] instead.
     pub fn current_shape(&'a mut self) -> Option<&'a mut Shape> {
+        if let Some(id) = self.current_id {
+            self.mark_dirty(id);
+        }
         self.current_shape.as_deref_mut()
     }
 
+    pub fn add_shape_child(&mut self, id: Uuid) {
+        if let Some(shape) = self.current_shape.as_deref_mut() {
+            self.dirty_children
+                .entry(shape.id)
+                .or_insert_with(|| shape.children.clone());
+            shape.add_child(id);
+            let id = shape.id;
+            self.invalidate_cache(id);
+        }
+    }
+
+    pub fn clear_shape_children(&mut self) {
+        if let Some(shape) = self.current_shape.as_deref_mut() {
+            self.dirty_children
+                .entry(shape.id)
+                .or_insert_with(|| shape.children.clone());
+            shape.clear_children();
+            let id = shape.id;
+            self.invalidate_cache(id);
+        }
+    }
+
+    /// Replaces the shapes with the same ids, or adds them, along with all their properties.
+    pub fn set_shapes(&mut self, shapes: Vec<Shape>) {
+        // The current shape could be replaced or moved when adding shapes to the map
+        self.current_id = None;
+        self.current_shape = None;
+
+        for shape in shapes {
+            let id = shape.id;
+            match self.shapes.get(&id).map(|old| old.children.clone()) {
+                Some(old_children) => {
+                    if old_children != shape.children {
+                        self.dirty_children.entry(id).or_insert(old_children);
+                    }
+                    self.mark_dirty(id);
+                }
+                // New shapes did not paint anything before
+                None => {
+                    self.invalidate_cache(id);
+                    self.dirty_shapes.insert(id, None);
+                }
+            }
+            self.shapes.insert(id, shape);
+        }
+    }
+
+    /// Removes the shape. Its children that have not been moved to another shape are removed
+    /// too, along with their descendants, as nothing would paint them anymore.
+    pub fn delete_shape(&mut self, id: Uuid) {
+        self.update_index();
+        // The children of the shape are not painted anymore either
+        join_damage(&mut self.damage, subtree_visual_bounds(&id, &self.shapes));
+
+        if let Some(parent_id) = self.render_state.index.parent(&id) {
+            if let Some(parent) = self.shapes.get_mut(&parent_id) {
+                parent.children.retain(|child_id| *child_id != id);
+            }
+        }
+
+        let index = &self.render_state.index;
+        let orphan_ids: Vec<Uuid> = match self.shapes.get(&id) {
+            Some(shape) => shape
+                .children
+                .iter()
+                .filter(|child_id| index.parent(child_id).unwrap_or(id) == id)
+                .copied()
+                .collect(),
+            None => vec![],
+        };
+
+        let mut image_ids = self.remove_shape(&id);
+        for orphan_id in orphan_ids {
+            image_ids.extend(self.remove_subtree(orphan_id));
+        }
+        self.release_unused_images(image_ids);
+    }
+
+    /// Removes the shape and all its descendants.
+    pub fn delete_shapes_subtree(&mut self, id: Uuid) {
+        let children = match self.shapes.get(&id) {
+            Some(shape) => shape.children.clone(),
+            None => return,
+        };
+
+        self.delete_shape(id);
+        let mut image_ids = HashSet::new();
+        for child_id in children {
+            image_ids.extend(self.remove_subtree(child_id));
+        }
+        self.release_unused_images(image_ids);
+    }
+
+    /// Removes the shape and all its descendants from the state, and returns the ids of the
+    /// images they used.
+    fn remove_subtree(&mut self, id: Uuid) -> HashSet<Uuid> {
+        let mut removed = HashSet::new();
+        let mut image_ids = HashSet::new();
+        let mut pending = vec![id];
+        while let Some(id) = pending.pop() {
+            if !removed.insert(id) {
+                continue;
+            }
+            if let Some(shape) = self.shapes.get(&id) {
+                pending.extend(shape.children.iter());
+            }
+            image_ids.extend(self.remove_shape(&id));
+        }
+        image_ids
+    }
+
+    /// Removes the shape from the state, and returns the ids of the images it used.
+    fn remove_shape(&mut self, id: &Uuid) -> HashSet<Uuid> {
+        if self.current_id == Some(*id) {
+            self.current_id = None;
+            self.current_shape = None;
+        }
+
+        // The area it painted before being changed has to be painted again
+        if let Some(old_bounds) = self.dirty_shapes.remove(id) {
+            join_damage(&mut self.damage, old_bounds);
+        }
+        self.dirty_children.remove(id);
+        self.invalidate_cache(*id);
+        self.render_state.index.remove(id);
+
+        match self.shapes.remove(id) {
+            Some(shape) => shape.image_ids().collect(),
+            None => HashSet::new(),
+        }
+    }
+
+    fn release_unused_images(&mut self, image_ids: HashSet<Uuid>) {
+        if image_ids.is_empty() {
+            return;
+        }
+
+        let used_ids: HashSet<Uuid> = self.shapes.values().flat_map(Shape::image_ids).collect();
+        for id in image_ids.difference(&used_ids) {
+            self.render_state.images.remove(id);
+        }
+    }
+
     pub fn set_background_color(&mut self, color: skia::Color) {
         self.render_state.set_background_color(color);
     }
 }
+
+fn join_damage(damage: &mut Option<math::Rect>, rect: Option<math::Rect>) {
+    match (damage.as_mut(), rect) {
+        (Some(damage), Some(rect)) => damage.join(rect),
+        (None, Some(rect)) => *damage = Some(rect),
+        (_, None) => {}
+    }
+}
+
+/// Returns the children that were added, removed or moved between both lists, that is,
+/// the ones between their common beginning and end.
+fn changed_children<'b>(old: &'b [Uuid], new: &'b [Uuid]) -> impl Iterator<Item = &'b Uuid> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    old[prefix..old.len() - suffix]
+        .iter()
+        .chain(new[prefix..new.len() - suffix].iter())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shapes::{BoolType, Fill, ImageScaleMode};
+
+    fn png_bytes() -> Vec<u8> {
+        let mut surface = skia::surfaces::raster_n32_premul((1, 1)).unwrap();
+        let image = surface.image_snapshot();
+        let data = image
+            .encode(None, skia::EncodedImageFormat::PNG, None)
+            .unwrap();
+        data.as_bytes().to_vec()
+    }
+
+    #[test]
+    fn deleting_a_subtree_removes_its_shapes_and_unused_images() {
+        let mut state = State::new_raster(100, 100, 8).unwrap();
+        let (group_id, child_id, image_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(group_id);
+        let mut group = Shape::new(group_id);
+        group.add_child(child_id);
+        let mut child = Shape::new(child_id);
+        child.set_selrect(10., 10., 20., 20.);
+        child.add_fill(Fill::new_image_fill(
+            image_id,
+            255,
+            (1, 1),
+            ImageScaleMode::Fill,
+            1.,
+        ));
+        for shape in [root, group, child] {
+            state.shapes.insert(shape.id, shape);
+        }
+        state.add_image(image_id, &png_bytes()).unwrap();
+        state.render_all(true).unwrap();
+
+        state.delete_shapes_subtree(group_id);
+
+        assert_eq!(state.shapes.len(), 1);
+        assert!(state.shapes[&Uuid::nil()].children.is_empty());
+        assert!(!state.render_state.has_image(&image_id));
+        assert_eq!(
+            state.take_damage(),
+            Some(math::Rect::from_ltrb(10., 10., 20., 20.))
+        );
+    }
+
+    #[test]
+    fn deleting_a_shape_removes_the_children_it_still_has() {
+        let mut state = State::new_raster(100, 100, 8).unwrap();
+        let [group_id, kept_id, moved_id, image_id] = [(); 4].map(|_| Uuid::new_v4());
+
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(group_id);
+        let mut group = Shape::new(group_id);
+        group.add_child(kept_id);
+        group.add_child(moved_id);
+        let mut kept = Shape::new(kept_id);
+        kept.add_fill(Fill::new_image_fill(
+            image_id,
+            255,
+            (1, 1),
+            ImageScaleMode::Fill,
+            1.,
+        ));
+        let moved = Shape::new(moved_id);
+        state.set_shapes(vec![root.clone(), group, kept, moved]);
+        state.add_image(image_id, &png_bytes()).unwrap();
+        state.update_index();
+
+        // Moved out of the group, which has not been sent again
+        root.add_child(moved_id);
+        state.set_shapes(vec![root]);
+        state.delete_shape(group_id);
+
+        assert!(!state.shapes.contains_key(&kept_id));
+        assert!(!state.render_state.has_image(&image_id));
+        assert!(state.shapes.contains_key(&moved_id));
+        assert_eq!(state.shapes[&Uuid::nil()].children, vec![moved_id]);
+    }
+
+    #[test]
+    fn changing_a_shape_drops_its_cache_and_the_bool_results_using_it() {
+        let mut state = State::new_raster(100, 100, 8).unwrap();
+        let (bool_id, child_id) = (Uuid::new_v4(), Uuid::new_v4());
+
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(bool_id);
+        let mut bool_shape = Shape::new(bool_id);
+        bool_shape.set_bool_type(BoolType::Union);
+        bool_shape.add_child(child_id);
+        let mut child = Shape::new(child_id);
+        child.set_selrect(10., 10., 20., 20.);
+        child.add_fill(Fill::Solid(skia::Color::RED));
+        state.set_shapes(vec![root, bool_shape, child.clone()]);
+        state.render_all(true).unwrap();
+        assert!(state.render_state.cache.contains(&child_id));
+
+        child.set_selrect(30., 30., 40., 40.);
+        state.set_shapes(vec![child]);
+        assert!(!state.render_state.cache.contains(&child_id));
+
+        let bool_shape = &state.shapes[&bool_id];
+        let path = state
+            .render_state
+            .cache
+            .get(&bool_id)
+            .bool_path(bool_shape, &state.shapes)
+            .unwrap();
+        assert_eq!(
+            path.to_skia_path().bounds(),
+            &skia::Rect::from_ltrb(30., 30., 40., 40.)
+        );
+    }
+
+    #[test]
+    fn bools_have_the_outline_of_their_children_without_being_rendered() {
+        let mut state = State::new_raster(100, 100, 8).unwrap();
+        let [bool_id, a, b] = [(); 3].map(|_| Uuid::new_v4());
+
+        let mut root = Shape::new(Uuid::nil());
+        root.add_child(bool_id);
+        let mut bool_shape = Shape::new(bool_id);
+        bool_shape.set_bool_type(BoolType::Difference);
+        bool_shape.add_child(a);
+        bool_shape.add_child(b);
+        let mut first = Shape::new(a);
+        first.set_selrect(0., 0., 100., 100.);
+        let mut second = Shape::new(b);
+        second.set_selrect(50., 0., 150., 100.);
+        state.set_shapes(vec![root, bool_shape, first, second.clone()]);
+        state.update_index();
+
+        let outline = state.shapes[&bool_id].to_world_path().unwrap();
+        assert_eq!(outline.bounds(), &skia::Rect::from_ltrb(0., 0., 50., 100.));
+
+        second.set_selrect(0., 50., 100., 150.);
+        state.set_shapes(vec![second]);
+        state.update_index();
+
+        let outline = state.shapes[&bool_id].to_world_path().unwrap();
+        assert_eq!(outline.bounds(), &skia::Rect::from_ltrb(0., 0., 100., 50.));
+    }
+
+    #[test]
+    fn changed_children_skip_the_common_beginning_and_end() {
+        let [a, b, c, d] = [(); 4].map(|_| Uuid::new_v4());
+
+        let inserted: Vec<_> = changed_children(&[a, b, c], &[a, d, b, c]).collect();
+        assert_eq!(inserted, vec![&d]);
+
+        let swapped: Vec<_> = changed_children(&[a, b, c], &[a, c, b]).collect();
+        assert_eq!(swapped, vec![&b, &c, &c, &b]);
+
+        assert_eq!(changed_children(&[a, b], &[a, b]).count(), 0);
+    }
+}
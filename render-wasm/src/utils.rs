@@ -5,3 +5,29 @@ pub fn uuid_from_u32_quartet(a: u32, b: u32, c: u32, d: u32) -> Uuid {
     let lo: u64 = ((c as u64) << 32) | d as u64;
     Uuid::from_u64_pair(hi, lo)
 }
+
+/// Reads a UUID serialized as four little endian `u32` (16 bytes), like the ones
+/// passed to [uuid_from_u32_quartet].
+pub fn uuid_from_u32_quartet_bytes(bytes: &[u8]) -> Uuid {
+    let read_u32 =
+        |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    uuid_from_u32_quartet(read_u32(0), read_u32(4), read_u32(8), read_u32(12))
+}
+
+pub fn uuid_to_u32_quartet(id: &Uuid) -> (u32, u32, u32, u32) {
+    let (hi, lo) = id.as_u64_pair();
+    ((hi >> 32) as u32, hi as u32, (lo >> 32) as u32, lo as u32)
+}
+
+/// Serializes the UUIDs as four little endian `u32` each, the inverse of
+/// [uuid_from_u32_quartet_bytes].
+pub fn uuids_to_u32_quartet_bytes(ids: &[Uuid]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ids.len() * 16);
+    for id in ids {
+        let (a, b, c, d) = uuid_to_u32_quartet(id);
+        for part in [a, b, c, d] {
+            bytes.extend_from_slice(&part.to_le_bytes());
+        }
+    }
+    bytes
+}